@@ -2,16 +2,29 @@
 
 use std::net::{Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::Client;
+use aws_sdk_s3::model::{ServerSideEncryption, StorageClass};
 use aws_types::sdk_config::SdkConfig;
 use clap::Parser;
-use tracing::info;
+use tracing::{info, warn};
 
+use crate::server::auth::{Access, Credentials};
+use crate::server::crypto::{EncryptedStore, KeyProvider, Keyring};
+#[cfg(feature = "git")]
+use crate::server::git::GitStore;
+use crate::server::layered::LayeredStore;
+use crate::server::name::EnvName;
 use crate::server::resolver::{AwsSecrets, ResolverChain};
-use crate::server::{server, state::State, storage::Storage};
+#[cfg(feature = "sql")]
+use crate::server::sql::SqlStore;
+use crate::server::storage::{s3_client, LocalStore, S3Store, TemplateStore};
+#[cfg(feature = "tls")]
+use crate::server::tls::{serve_tls, TlsFiles, TlsServer};
+use crate::server::{server, state::State};
 
 /// Creates an AWS SDK default config object.
 pub async fn default_aws_config() -> SdkConfig {
@@ -19,6 +32,36 @@ pub async fn default_aws_config() -> SdkConfig {
     aws_config::from_env().region(region_provider).load().await
 }
 
+/// Parses a `KEY=VALUE` S3 object tag from the command line.
+fn parse_tag(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .context("expected S3 object tag of the form KEY=VALUE")?;
+    Ok((key.into(), value.into()))
+}
+
+/// Parses a `NAMESPACE=SECRET` pair from the command line.
+fn parse_namespace_secret(s: &str) -> Result<(EnvName, String)> {
+    let (namespace, secret) = s
+        .split_once('=')
+        .context("expected namespace secret of the form NAMESPACE=SECRET")?;
+    Ok((namespace.parse()?, secret.into()))
+}
+
+/// Parses an `IDENTITY=SCOPE` pair from the command line, where the scope is
+/// either `*` for every environment or a namespace.
+#[cfg(feature = "tls")]
+fn parse_identity_access(s: &str) -> Result<(String, Access)> {
+    let (identity, scope) = s
+        .split_once('=')
+        .context("expected client identity of the form IDENTITY=SCOPE")?;
+    let access = match scope {
+        "*" => Access::all(),
+        namespace => Access::namespace(namespace.parse()?),
+    };
+    Ok((identity.into(), access))
+}
+
 /// A simple, self-hosted, high-performance remote configuration service.
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = None)]
@@ -31,21 +74,287 @@ pub struct Args {
     #[clap(long, env = "CADRE_SECRET")]
     secret: String,
 
+    /// Additional secret granting access only to environments nested under a
+    /// namespace, given as `NAMESPACE=SECRET`. May be repeated.
+    #[clap(
+        long = "namespace-secret",
+        env = "CADRE_NAMESPACE_SECRETS",
+        value_delimiter = ',',
+        parse(try_from_str = parse_namespace_secret)
+    )]
+    namespace_secrets: Vec<(EnvName, String)>,
+
+    /// Additional secret granting access to every environment, but with the
+    /// values of secret resolvers redacted from populated configs. May be
+    /// repeated.
+    #[clap(
+        long = "redacted-secret",
+        env = "CADRE_REDACTED_SECRETS",
+        value_delimiter = ','
+    )]
+    redacted_secrets: Vec<String>,
+
+    /// PEM file with a certificate chain, to serve HTTPS instead of HTTP. The
+    /// file is reloaded whenever it changes.
+    #[cfg(feature = "tls")]
+    #[clap(long, env = "CADRE_TLS_CERT", requires = "tls-key", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key for `--tls-cert`.
+    #[cfg(feature = "tls")]
+    #[clap(long, env = "CADRE_TLS_KEY", requires = "tls-cert", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file with roots to verify client certificates against. Clients
+    /// without a certificate must still present a secret.
+    #[cfg(feature = "tls")]
+    #[clap(
+        long,
+        env = "CADRE_TLS_CLIENT_CA",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Rejects clients that do not present a valid certificate.
+    #[cfg(feature = "tls")]
+    #[clap(
+        long,
+        env = "CADRE_TLS_REQUIRE_CLIENT_CERT",
+        requires = "tls-client-ca"
+    )]
+    tls_require_client_cert: bool,
+
+    /// Grants clients whose certificate has a given common name access to
+    /// `*` for everything, or a namespace, given as `IDENTITY=SCOPE`. May be
+    /// repeated.
+    #[cfg(feature = "tls")]
+    #[clap(
+        long = "client-identity",
+        env = "CADRE_CLIENT_IDENTITIES",
+        value_delimiter = ',',
+        requires = "tls-client-ca",
+        parse(try_from_str = parse_identity_access)
+    )]
+    client_identities: Vec<(String, Access)>,
+
     /// S3 bucket to use for persisting template JSON files.
     #[clap(long, env = "CADRE_BUCKET")]
     bucket: Option<String>,
 
+    /// Key prefix for template JSON files in the S3 bucket, allowing several
+    /// cadre instances to share one bucket.
+    #[clap(long, default_value = "", env = "CADRE_PREFIX")]
+    prefix: String,
+
+    /// Server-side encryption mode for S3 objects, such as `aws:kms` or
+    /// `AES256`.
+    #[clap(long, env = "CADRE_S3_SSE")]
+    s3_sse: Option<String>,
+
+    /// KMS key for server-side encryption of S3 objects. Implies `aws:kms`
+    /// unless another mode is given.
+    #[clap(long, env = "CADRE_S3_KMS_KEY_ID")]
+    s3_kms_key_id: Option<String>,
+
+    /// Storage class for S3 objects, such as `STANDARD_IA`.
+    #[clap(long, env = "CADRE_S3_STORAGE_CLASS")]
+    s3_storage_class: Option<String>,
+
+    /// Tag to add to S3 objects, given as `KEY=VALUE`. May be repeated.
+    #[clap(
+        long = "s3-tag",
+        env = "CADRE_S3_TAGS",
+        value_delimiter = ',',
+        parse(try_from_str = parse_tag)
+    )]
+    s3_tags: Vec<(String, String)>,
+
+    /// Custom endpoint for an S3-compatible service such as MinIO, for
+    /// example `http://localhost:9000`.
+    #[clap(long, env = "CADRE_S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+
     /// Local directory to use for persisting template JSON files.
     #[clap(long, parse(from_os_str), env = "CADRE_LOCAL_DIR")]
     local_dir: Option<PathBuf>,
 
+    /// Local git repository to use for persisting template JSON files, with
+    /// each write recorded as a commit.
+    #[cfg(feature = "git")]
+    #[clap(long, parse(from_os_str), env = "CADRE_GIT_REPO")]
+    git_repo: Option<PathBuf>,
+
+    /// Branch of the git repository to commit to. Defaults to the branch
+    /// that `HEAD` points to.
+    #[cfg(feature = "git")]
+    #[clap(long, env = "CADRE_GIT_BRANCH")]
+    git_branch: Option<String>,
+
+    /// Name of a git remote to push to after every commit.
+    #[cfg(feature = "git")]
+    #[clap(long, env = "CADRE_GIT_REMOTE")]
+    git_remote: Option<String>,
+
+    /// Author name recorded on git commits.
+    #[cfg(feature = "git")]
+    #[clap(long, default_value = "cadre", env = "CADRE_GIT_AUTHOR_NAME")]
+    git_author_name: String,
+
+    /// Author email recorded on git commits.
+    #[cfg(feature = "git")]
+    #[clap(
+        long,
+        default_value = "cadre@localhost",
+        env = "CADRE_GIT_AUTHOR_EMAIL"
+    )]
+    git_author_email: String,
+
+    /// SQL database to use for persisting templates, their revisions and an
+    /// audit log, such as `sqlite://cadre.db?mode=rwc` or `postgres://...`.
+    #[cfg(feature = "sql")]
+    #[clap(long, env = "CADRE_DATABASE_URL")]
+    database_url: Option<String>,
+
+    /// Local directory that keeps a copy of every template, which serves reads
+    /// whenever the storage backend is unavailable. It is brought up to date
+    /// with the backend on startup, then every `--sync-interval` seconds, or
+    /// every minute if that is not set.
+    #[clap(long, parse(from_os_str), env = "CADRE_FALLBACK_DIR")]
+    fallback_dir: Option<PathBuf>,
+
+    /// File of keys used to encrypt templates at rest, with one `ID:KEY` line
+    /// per key. The last key encrypts new writes, while earlier ones can still
+    /// decrypt older templates.
+    #[clap(long, parse(from_os_str), env = "CADRE_ENCRYPTION_KEY_FILE")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// AWS KMS key used to encrypt templates at rest, instead of a key file.
+    #[cfg(feature = "kms")]
+    #[clap(long, env = "CADRE_KMS_KEY_ID")]
+    kms_key_id: Option<String>,
+
+    /// Re-encrypts all templates with the current key on startup, after a key
+    /// rotation or when enabling encryption on existing templates.
+    #[clap(long)]
+    reencrypt: bool,
+
     /// Sets a default templated JSON to be used for other environments
     /// to build upon. Ignored if left empty.
     #[clap(long, env = "CADRE_DEFAULT_TEMPLATE")]
-    default_template: Option<String>,
+    default_template: Option<EnvName>,
+
+    /// Keeps an in-memory mirror of all templates, polling storage for
+    /// changes at this interval in seconds. Disabled if left empty.
+    #[clap(long, env = "CADRE_SYNC_INTERVAL")]
+    sync_interval: Option<u64>,
+
+    /// Validates populated configs against their JSON Schemas when they are
+    /// read, not only templates when they are written.
+    #[clap(long, env = "CADRE_VALIDATE_CONFIGS")]
+    validate_configs: bool,
 }
 
 impl Args {
+    /// Create the storage backend selected by command-line flags.
+    async fn storage(&self, sdk_config: &SdkConfig) -> Result<Box<dyn TemplateStore>> {
+        let mut backends: Vec<Box<dyn TemplateStore>> = Vec::new();
+        if let Some(bucket) = &self.bucket {
+            backends.push(Box::new(self.s3_store(sdk_config, bucket)?));
+        }
+        if let Some(local_dir) = &self.local_dir {
+            backends.push(Box::new(LocalStore::new(local_dir)));
+        }
+        #[cfg(feature = "git")]
+        if let Some(git_repo) = &self.git_repo {
+            let mut git =
+                GitStore::new(git_repo).author(&self.git_author_name, &self.git_author_email);
+            if let Some(branch) = &self.git_branch {
+                git = git.branch(branch);
+            }
+            if let Some(remote) = &self.git_remote {
+                git = git.remote(remote);
+            }
+            backends.push(Box::new(git));
+        }
+        #[cfg(feature = "sql")]
+        if let Some(database_url) = &self.database_url {
+            backends.push(Box::new(SqlStore::connect(database_url).await?));
+        }
+
+        if backends.len() != 1 {
+            bail!("must specify exactly one storage backend, such as --bucket or --local-dir");
+        }
+        let primary = backends.pop().unwrap();
+
+        let mut storage = primary;
+        if let Some(fallback_dir) = &self.fallback_dir {
+            let layered = LayeredStore::new(storage, LocalStore::new(fallback_dir));
+            // The fallback directory still serves reads if the primary is
+            // down, so a failure here should not prevent startup.
+            if let Err(err) = layered.reconcile().await {
+                warn!(?err, "problem reconciling fallback directory");
+            }
+            let layered = Arc::new(layered);
+            layered.spawn_reconcile(Duration::from_secs(self.sync_interval.unwrap_or(60)));
+            storage = Box::new(layered);
+        }
+
+        // Encryption wraps every other layer, so that no copy is in plaintext.
+        if let Some(keys) = self.key_provider(sdk_config)? {
+            let encrypted = EncryptedStore::new(storage, keys);
+            if self.reencrypt {
+                encrypted.reencrypt().await?;
+            }
+            storage = Box::new(encrypted);
+        } else if self.reencrypt {
+            bail!("--reencrypt requires an encryption key");
+        }
+        Ok(storage)
+    }
+
+    /// Create an S3 store with the object options given by command-line flags.
+    fn s3_store(&self, sdk_config: &SdkConfig, bucket: &str) -> Result<S3Store> {
+        let client = s3_client(sdk_config, self.s3_endpoint.as_deref())?;
+        let mut store = S3Store::new(client, bucket, &self.prefix);
+        if let Some(sse) = &self.s3_sse {
+            if !ServerSideEncryption::values().contains(&sse.as_str()) {
+                bail!("unknown S3 server-side encryption mode {sse}");
+            }
+            store = store.server_side_encryption(sse.as_str().into());
+        }
+        if let Some(key_id) = &self.s3_kms_key_id {
+            store = store.kms_key_id(key_id);
+        }
+        if let Some(storage_class) = &self.s3_storage_class {
+            if !StorageClass::values().contains(&storage_class.as_str()) {
+                bail!("unknown S3 storage class {storage_class}");
+            }
+            store = store.storage_class(storage_class.as_str().into());
+        }
+        for (key, value) in &self.s3_tags {
+            store = store.tag(key, value);
+        }
+        Ok(store)
+    }
+
+    /// Create the source of encryption keys selected by command-line flags.
+    #[allow(unused_variables)]
+    fn key_provider(&self, sdk_config: &SdkConfig) -> Result<Option<KeyProvider>> {
+        #[cfg(feature = "kms")]
+        if let Some(key_id) = &self.kms_key_id {
+            if self.encryption_key_file.is_some() {
+                bail!("cannot use both --encryption-key-file and --kms-key-id");
+            }
+            let client = aws_sdk_kms::Client::new(sdk_config);
+            return Ok(Some(KeyProvider::Kms(client, key_id.into())));
+        }
+        match &self.encryption_key_file {
+            Some(path) => Ok(Some(KeyProvider::Local(Keyring::load(path)?))),
+            None => Ok(None),
+        }
+    }
+
     /// Run the action corresponding to this CLI command.
     pub async fn run(self) -> Result<()> {
         let sdk_config = default_aws_config().await;
@@ -53,20 +362,49 @@ impl Args {
         let mut chain = ResolverChain::new();
         chain.add(AwsSecrets::new(&sdk_config));
 
-        let storage = match (&self.bucket, &self.local_dir) {
-            (Some(bucket), None) => Storage::S3(Client::new(&sdk_config), bucket.into()),
-            (None, Some(local_dir)) => Storage::LocalFS(local_dir.into()),
-            _ => bail!("must specify exactly one of --bucket or --local-dir"),
-        };
+        let storage = self.storage(&sdk_config).await?;
+
+        let mut credentials = Credentials::new(&self.secret);
+        for (namespace, secret) in self.namespace_secrets {
+            credentials.add_namespace(namespace, &secret);
+        }
+        for secret in self.redacted_secrets {
+            credentials.add_secret(&secret, Access::all().redacted());
+        }
+        #[cfg(feature = "tls")]
+        for (identity, access) in self.client_identities {
+            credentials.add_identity(&identity, access);
+        }
 
         let logged_secret = self.secret.clone();
-        let state = State::new(chain, storage, self.default_template.as_deref());
-        let app = server(state, self.secret);
+        let mut state = State::new(chain, storage, self.default_template);
+        if let Some(interval) = self.sync_interval {
+            state = state.with_mirror(Duration::from_secs(interval)).await?;
+        }
+        if self.validate_configs {
+            state = state.with_config_validation();
+        }
+        // Share fingerprints of redacted secrets between replicas if possible.
+        if let Some(path) = &self.encryption_key_file {
+            state = state.with_redaction_key(Keyring::load(path)?.derive_key("redaction"));
+        }
+        let app = server(state, credentials);
 
         let addr: SocketAddr = (Ipv6Addr::UNSPECIFIED, self.port).into();
         info!(?addr, "running cadre");
         info!("visit frontend at {}?secret={}", addr, logged_secret);
 
+        #[cfg(feature = "tls")]
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            let tls = TlsServer::load(TlsFiles {
+                cert,
+                key,
+                client_ca: self.tls_client_ca,
+                require_client_cert: self.tls_require_client_cert,
+            })?;
+            return serve_tls(std::net::TcpListener::bind(addr)?, app, tls).await;
+        }
+
         axum::Server::bind(&addr)
             .serve(app.into_make_service())
             .await?;
@@ -1,80 +1,552 @@
 //! Implementation of the Rust client for cadre.
 
-use anyhow::{ensure, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use fallback::FallbackCache;
 use hyper::body::Buf;
 use hyper::client::HttpConnector;
 use hyper::header::CONTENT_TYPE;
-use hyper::{Body, Client, Request};
+use hyper::{Body, Client, Method, Request, StatusCode};
+pub use origins::Balance;
+use origins::Origins;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
+use tokio::sync::watch;
+use tokio::time::{self, error::Elapsed, Duration, Instant};
+use tracing::{info, warn};
+
+use crate::server::state::{Explanation, Listing};
+use crate::server::storage::{TemplateNotFound, TemplateStore};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod fallback;
+mod origins;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(feature = "tls")]
+type Connector = hyper_rustls::HttpsConnector<HttpConnector>;
+#[cfg(not(feature = "tls"))]
+type Connector = HttpConnector;
 
 /// An asynchronous client for the configuration store.
 #[derive(Clone)]
 pub struct CadreClient {
-    client: Client<HttpConnector>,
-    origin: String,
+    client: Client<Connector>,
+    origins: Arc<Origins>,
     secret: String,
+    request_timeout: Option<Duration>,
+    retries: u32,
+    backoff: (Duration, Duration),
+    eject_duration: Duration,
+    fallback: Option<Arc<FallbackCache>>,
+}
+
+/// Builder for a [`CadreClient`] with custom timeouts, retries, origins or
+/// TLS.
+///
+/// By default, connections time out after 10 seconds and requests after 30
+/// seconds. Reads are retried up to 3 times on connection errors, timeouts and
+/// server errors, with exponential backoff from 100 milliseconds to 5 seconds.
+/// With several origins, origins that refuse connections, or fail reads with
+/// timeouts or server errors, are ejected for 30 seconds at a time.
+#[derive(Clone)]
+pub struct ClientBuilder {
+    origins: Vec<String>,
+    secret: String,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    retries: u32,
+    backoff: (Duration, Duration),
+    balance: Balance,
+    eject_duration: Duration,
+    fallback: Option<Arc<dyn TemplateStore>>,
+    #[cfg(feature = "tls")]
+    tls: tls::TlsConfig,
+}
+
+impl ClientBuilder {
+    /// Set the timeout for establishing a connection, or `None` for no limit.
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set the timeout for each attempt at a request, including reading the
+    /// response body, or `None` for no limit.
+    pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Set how many times a failed read is retried. Writes are never retried.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the delay before the first retry, which doubles with each further
+    /// retry up to a maximum. Delays are randomly jittered by up to half.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = (initial, max);
+        self
+    }
+
+    /// Add another origin, such as a replica of the first.
+    ///
+    /// Requests are spread across origins, and fail over to another origin
+    /// when one cannot be connected to.
+    pub fn add_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.into());
+        self
+    }
+
+    /// Set how requests are spread across origins.
+    pub fn balance(mut self, balance: Balance) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    /// Set how long an origin that failed a request is skipped, before it is
+    /// checked with `/ping` and used again if healthy.
+    pub fn eject_duration(mut self, duration: Duration) -> Self {
+        self.eject_duration = duration;
+        self
+    }
+
+    /// Keep the last populated config loaded for each environment in a store,
+    /// such as a [`LocalStore`](crate::server::storage::LocalStore), and fall
+    /// back to it when the server is unavailable. Configs are only saved when
+    /// they change, under a prefix unique to the client's origins and secret,
+    /// so several clients can share a store.
+    ///
+    /// Configs may contain secrets, so consider wrapping the store in an
+    /// [`EncryptedStore`](crate::server::crypto::EncryptedStore).
+    pub fn fallback_cache(mut self, store: impl TemplateStore + 'static) -> Self {
+        self.fallback = Some(Arc::new(store));
+        self
+    }
+
+    /// Use custom settings for TLS, such as private roots or a client
+    /// certificate.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: tls::TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Build the client.
+    pub fn build(self) -> Result<CadreClient> {
+        let mut http = HttpConnector::new();
+        http.set_nodelay(true);
+        http.set_connect_timeout(self.connect_timeout);
+        // Let the TLS connector handle `https` URLs, since it wraps this one.
+        #[cfg(feature = "tls")]
+        http.enforce_http(false);
+
+        #[cfg(feature = "tls")]
+        let connector = self.tls.connector(http)?;
+        #[cfg(not(feature = "tls"))]
+        let connector = http;
+
+        Ok(CadreClient {
+            client: Client::builder().build(connector),
+            fallback: self
+                .fallback
+                .map(|store| Arc::new(FallbackCache::new(store, &self.origins, &self.secret))),
+            origins: Arc::new(Origins::new(self.origins, self.balance)),
+            secret: self.secret,
+            request_timeout: self.request_timeout,
+            retries: self.retries,
+            backoff: self.backoff,
+            eject_duration: self.eject_duration,
+        })
+    }
+
+    /// Build a blocking client.
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking(self) -> Result<blocking::CadreClient> {
+        blocking::CadreClient::from_async(self.build()?)
+    }
+}
+
+/// Error for a response with an unsuccessful status.
+///
+/// Requests that fail this way can be told apart by downcasting the error,
+/// for example to check for `404 Not Found`. Server errors may be retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatusError(StatusCode);
+
+impl StatusError {
+    /// Return the status of the response.
+    pub fn status(&self) -> StatusCode {
+        self.0
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cadre request failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// A populated configuration, along with where it was loaded from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadedConfig {
+    /// The populated configuration.
+    pub config: Value,
+
+    /// Time since the config was last fetched, to within a minute, if the
+    /// server was unavailable and it was read from the fallback cache instead.
+    pub cache_age: Option<Duration>,
+}
+
+impl LoadedConfig {
+    fn fresh(config: Value) -> Self {
+        Self {
+            config,
+            cache_age: None,
+        }
+    }
 }
 
 impl CadreClient {
-    /// Create a new client object pointing at a given HTTP origin.
+    /// Create a new client object pointing at a given HTTP origin, with
+    /// default timeouts and retries.
+    ///
+    /// With the `tls` feature, the origin may also use HTTPS, verified against
+    /// the platform's native root certificates.
     pub fn new(origin: &str, secret: &str) -> Self {
-        let mut connector = HttpConnector::new();
-        connector.set_nodelay(true);
+        Self::builder(origin, secret)
+            .build()
+            .expect("default client configuration is valid")
+    }
 
-        Self {
-            client: Client::builder().build(connector),
-            origin: origin.into(),
+    /// Create a new client object pointing at an HTTP or HTTPS origin, with
+    /// custom settings for TLS, such as private roots or a client certificate.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(origin: &str, secret: &str, tls: &tls::TlsConfig) -> Result<Self> {
+        Self::builder(origin, secret).tls(tls.clone()).build()
+    }
+
+    /// Start building a client with custom settings.
+    pub fn builder(origin: &str, secret: &str) -> ClientBuilder {
+        ClientBuilder {
+            origins: vec![origin.into()],
             secret: secret.into(),
+            connect_timeout: Some(Duration::from_secs(10)),
+            request_timeout: Some(Duration::from_secs(30)),
+            retries: 3,
+            backoff: (Duration::from_millis(100), Duration::from_secs(5)),
+            balance: Balance::RoundRobin,
+            eject_duration: Duration::from_secs(30),
+            fallback: None,
+            #[cfg(feature = "tls")]
+            tls: tls::TlsConfig::new(),
         }
     }
 
-    async fn send(&self, req: Request<Body>) -> Result<impl Buf> {
-        let resp = self.client.request(req).await?;
+    /// Send a request built for an origin, failing over to other origins if
+    /// it cannot be connected to.
+    ///
+    /// Reads also fail over if they time out or fail with a server error.
+    async fn send(&self, build: impl Fn(&str) -> Result<Request<Body>>) -> Result<impl Buf> {
+        let mut last_err = None;
+        for index in self.origins.order() {
+            let start = Instant::now();
+            let req = build(self.origins.url(index))?;
+            let is_read = req.method() == Method::GET;
+            match send_request(&self.client, req, self.request_timeout).await {
+                Ok(resp) => {
+                    self.origins.record_latency(index, start.elapsed());
+                    return Ok(resp);
+                }
+                // Failing over is always safe if the request never reached
+                // the origin, and otherwise only if it has no side effects.
+                Err(err)
+                    if self.origins.len() > 1
+                        && (is_connect_error(&err) || is_read && is_transient(&err)) =>
+                {
+                    warn!(origin = self.origins.url(index), %err, "failing over to another origin");
+                    self.eject(index);
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.expect("client has at least one origin"))
+    }
 
-        // check response status
-        let status = resp.status();
-        ensure!(!status.is_server_error(), "cadre server error: {status}");
-        ensure!(status.is_success(), "cadre get request failed: {status}");
+    /// Stop sending requests to an origin until it passes a health check.
+    fn eject(&self, index: usize) {
+        if !self.origins.eject(index) {
+            return;
+        }
+        let client = self.client.clone();
+        let origins = Arc::downgrade(&self.origins);
+        let (interval, timeout) = (self.eject_duration, self.request_timeout);
+        tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                // Stop checking once the client has been dropped.
+                let origins = match origins.upgrade() {
+                    Some(origins) => origins,
+                    None => return,
+                };
+                let url = origins.url(index);
+                let req = Request::get(format!("{url}/ping")).body(Body::empty());
+                let start = Instant::now();
+                match send_request(&client, req.expect("valid ping request"), timeout).await {
+                    Ok(_) => {
+                        info!(origin = url, "origin is healthy again");
+                        origins.record_latency(index, start.elapsed());
+                        origins.restore(index);
+                        return;
+                    }
+                    Err(err) => warn!(origin = url, %err, "origin is still unhealthy"),
+                }
+            }
+        });
+    }
 
-        // asynchronously aggregate the chunks of the body and create serde json
-        Ok(hyper::body::aggregate(resp).await?)
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .send(|origin| {
+                    Ok(Request::builder()
+                        .method("GET")
+                        .header("X-Cadre-Secret", &self.secret)
+                        .uri(format!("{origin}{path}"))
+                        .body(Body::empty())?)
+                })
+                .await;
+            let err = match result {
+                Ok(resp) => {
+                    let mut de = serde_json::Deserializer::from_reader(resp.reader());
+                    return Ok(serde_path_to_error::deserialize(&mut de)?);
+                }
+                Err(err) if attempt < self.retries && is_transient(&err) => err,
+                Err(err) => return Err(err),
+            };
+            attempt += 1;
+            let delay = self.retry_delay(attempt);
+            warn!(%path, attempt, ?delay, %err, "retrying cadre request");
+            time::sleep(delay).await;
+        }
     }
 
-    async fn get<T: DeserializeOwned>(&self, uri: &str) -> Result<T> {
-        let req = Request::builder()
-            .method("GET")
-            .header("X-Cadre-Secret", &self.secret)
-            .uri(uri)
-            .body(Body::empty())?;
-        let resp = self.send(req).await?;
-        Ok(serde_json::from_reader(resp.reader())?)
+    /// Return a jittered exponential backoff delay before a given retry.
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let (initial, max) = self.backoff;
+        let delay = initial
+            .saturating_mul(2_u32.saturating_pow(attempt - 1))
+            .min(max);
+        delay.mul_f64(0.5 + fastrand::f64() * 0.5)
     }
 
     /// Fetch the raw JSON source for a template.
     pub async fn read_template(&self, env: &str) -> Result<Value> {
-        self.get(&format!("{}/t/{}", self.origin, env)).await
+        self.get(&format!("/t/{env}")).await
     }
 
     /// Write the value for a template.
     pub async fn write_template(&self, env: &str, template: &Value) -> Result<()> {
-        let req = Request::builder()
-            .method("PUT")
-            .uri(format!("{}/t/{}", self.origin, env))
-            .header(CONTENT_TYPE, "application/json")
-            .header("X-Cadre-Secret", &self.secret)
-            .body(serde_json::to_string(template)?.into())?;
-        self.send(req).await?;
+        let body = serde_json::to_string(template)?;
+        self.send(|origin| {
+            Ok(Request::builder()
+                .method("PUT")
+                .uri(format!("{origin}/t/{env}"))
+                .header(CONTENT_TYPE, "application/json")
+                .header("X-Cadre-Secret", &self.secret)
+                .body(body.clone().into())?)
+        })
+        .await?;
         Ok(())
     }
 
     /// Read a populated configuration with templated and default values.
+    ///
+    /// If the client has a fallback cache, it is used when the server is
+    /// unavailable.
     pub async fn load_config(&self, env: &str) -> Result<Value> {
-        self.get(&format!("{}/c/{}", self.origin, env)).await
+        Ok(self.load_config_or_cached(env).await?.config)
+    }
+
+    /// Read several populated configurations in one request, keyed by
+    /// environment. Fails if any of them fails to load.
+    pub async fn load_configs(&self, envs: &[&str]) -> Result<BTreeMap<String, Value>> {
+        self.get(&format!("/c?envs={}", envs.join(","))).await
+    }
+
+    /// Read a populated configuration, deserialized into a given type.
+    ///
+    /// If deserialization fails, the error includes the path to the offending
+    /// value, such as `db.port`.
+    pub async fn load_config_as<T: DeserializeOwned>(&self, env: &str) -> Result<T> {
+        let config = self.load_config(env).await?;
+        Ok(serde_path_to_error::deserialize(config)?)
+    }
+
+    /// Watch a populated configuration for changes, deserialized into a given
+    /// type.
+    ///
+    /// The config is loaded once, then polled at an interval in the background
+    /// until every receiver is dropped. Receivers are only notified when the
+    /// value changes. Polls that fail to load or deserialize are logged and
+    /// skipped, keeping the last good value.
+    pub async fn watch_config_as<T>(
+        &self,
+        env: &str,
+        interval: Duration,
+    ) -> Result<watch::Receiver<T>>
+    where
+        T: DeserializeOwned + PartialEq + Send + Sync + 'static,
+    {
+        let (tx, rx) = watch::channel(self.load_config_as(env).await?);
+        let client = self.clone();
+        let path = format!("/c/{env}");
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    _ = time::sleep(interval) => (),
+                }
+                match client.get::<T>(&path).await {
+                    Ok(value) => {
+                        if *tx.borrow() != value && tx.send(value).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => warn!(%path, %err, "could not reload watched config"),
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    /// Read a populated configuration, reporting whether it came from the
+    /// fallback cache.
+    pub async fn load_config_or_cached(&self, env: &str) -> Result<LoadedConfig> {
+        let result = self.get(&format!("/c/{env}")).await;
+        let fallback = match &self.fallback {
+            Some(fallback) => fallback,
+            None => return result.map(LoadedConfig::fresh),
+        };
+        match result {
+            Ok(config) => {
+                fallback.save(env, &config).await;
+                Ok(LoadedConfig::fresh(config))
+            }
+            Err(err) if is_transient(&err) => match fallback.load(env).await {
+                Ok((config, age)) => {
+                    warn!(%env, ?age, %err, "cadre is unavailable, using cached config");
+                    Ok(LoadedConfig {
+                        config,
+                        cache_age: Some(age),
+                    })
+                }
+                Err(cache_err) => {
+                    if !cache_err.is::<TemplateNotFound>() {
+                        warn!(%env, ?cache_err, "could not read fallback cache");
+                    }
+                    Err(err)
+                }
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Populate a candidate template as [`load_config`](Self::load_config)
+    /// would, without saving it.
+    pub async fn preview_config(&self, env: &str, template: &Value) -> Result<Value> {
+        let body = serde_json::to_string(template)?;
+        let resp = self
+            .send(|origin| {
+                Ok(Request::builder()
+                    .method("POST")
+                    .uri(format!("{origin}/preview/{env}"))
+                    .header(CONTENT_TYPE, "application/json")
+                    .header("X-Cadre-Secret", &self.secret)
+                    .body(body.clone().into())?)
+            })
+            .await?;
+        Ok(serde_json::from_reader(resp.reader())?)
+    }
+
+    /// Remove a template.
+    pub async fn delete_template(&self, env: &str) -> Result<()> {
+        self.send(|origin| {
+            Ok(Request::builder()
+                .method("DELETE")
+                .uri(format!("{origin}/t/{env}"))
+                .header("X-Cadre-Secret", &self.secret)
+                .body(Body::empty())?)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Read a populated configuration along with the source of each value.
+    pub async fn explain_config(&self, env: &str) -> Result<Explanation> {
+        self.get(&format!("/explain/{env}")).await
     }
 
     /// List all available configuration environment names.
     pub async fn list_configs(&self) -> Result<Vec<String>> {
-        self.get(&format!("{}/c", self.origin)).await
+        self.get("/c").await
+    }
+
+    /// List the environments and namespaces directly inside a namespace.
+    ///
+    /// An empty namespace lists the top level of the hierarchy.
+    pub async fn list_namespace(&self, namespace: &str) -> Result<Listing> {
+        self.get(&format!("/n/{namespace}")).await
+    }
+}
+
+/// Return true if a failed request may succeed when retried.
+fn is_transient(err: &anyhow::Error) -> bool {
+    let server_error = matches!(
+        err.downcast_ref::<StatusError>(),
+        Some(err) if err.status().is_server_error()
+    );
+    err.is::<hyper::Error>() || err.is::<Elapsed>() || server_error
+}
+
+/// Return true if a request failed before it could reach the server.
+fn is_connect_error(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<hyper::Error>(), Some(err) if err.is_connect())
+}
+
+/// Send a request, checking the status of its response and reading its body.
+async fn send_request(
+    client: &Client<Connector>,
+    req: Request<Body>,
+    timeout: Option<Duration>,
+) -> Result<impl Buf> {
+    let send = async {
+        let resp = client.request(req).await?;
+
+        // check response status
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(StatusError(status).into());
+        }
+
+        // asynchronously aggregate the chunks of the body and create serde json
+        Ok(hyper::body::aggregate(resp).await?)
+    };
+    match timeout {
+        Some(timeout) => time::timeout(timeout, send)
+            .await
+            .with_context(|| format!("cadre request timed out after {timeout:?}"))?,
+        None => send.await,
     }
 }
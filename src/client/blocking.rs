@@ -0,0 +1,114 @@
+//! Blocking client for cadre, for use outside of an async runtime.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::runtime::{Builder, Runtime};
+
+use super::LoadedConfig;
+use crate::server::state::{Explanation, Listing};
+
+/// A blocking client for the configuration store.
+///
+/// This wraps the asynchronous [`CadreClient`](super::CadreClient), running
+/// each request to completion on a private runtime. That runtime has a worker
+/// thread of its own, so health checks of ejected origins keep running between
+/// requests. Its methods must not be called from within an async context,
+/// since they block the thread.
+#[derive(Clone)]
+pub struct CadreClient {
+    inner: super::CadreClient,
+    runtime: Arc<Runtime>,
+}
+
+impl CadreClient {
+    /// Create a new client object pointing at a given HTTP origin, with
+    /// default timeouts and retries.
+    pub fn new(origin: &str, secret: &str) -> Result<Self> {
+        Self::from_async(super::CadreClient::new(origin, secret))
+    }
+
+    /// Create a new client object with custom settings for TLS.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(origin: &str, secret: &str, tls: &super::tls::TlsConfig) -> Result<Self> {
+        Self::from_async(super::CadreClient::with_tls(origin, secret, tls)?)
+    }
+
+    /// Wrap an asynchronous client, such as one made with
+    /// [`CadreClient::builder`](super::CadreClient::builder).
+    pub fn from_async(inner: super::CadreClient) -> Result<Self> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Fetch the raw JSON source for a template.
+    pub fn read_template(&self, env: &str) -> Result<Value> {
+        self.runtime.block_on(self.inner.read_template(env))
+    }
+
+    /// Write the value for a template.
+    pub fn write_template(&self, env: &str, template: &Value) -> Result<()> {
+        self.runtime
+            .block_on(self.inner.write_template(env, template))
+    }
+
+    /// Remove a template.
+    pub fn delete_template(&self, env: &str) -> Result<()> {
+        self.runtime.block_on(self.inner.delete_template(env))
+    }
+
+    /// Read a populated configuration with templated and default values.
+    pub fn load_config(&self, env: &str) -> Result<Value> {
+        self.runtime.block_on(self.inner.load_config(env))
+    }
+
+    /// Read several populated configurations in one request, keyed by
+    /// environment.
+    pub fn load_configs(&self, envs: &[&str]) -> Result<BTreeMap<String, Value>> {
+        self.runtime.block_on(self.inner.load_configs(envs))
+    }
+
+    /// Read a populated configuration, deserialized into a given type.
+    pub fn load_config_as<T: DeserializeOwned>(&self, env: &str) -> Result<T> {
+        self.runtime.block_on(self.inner.load_config_as(env))
+    }
+
+    /// Read a populated configuration, reporting whether it came from the
+    /// fallback cache.
+    pub fn load_config_or_cached(&self, env: &str) -> Result<LoadedConfig> {
+        self.runtime.block_on(self.inner.load_config_or_cached(env))
+    }
+
+    /// Populate a candidate template as [`load_config`](Self::load_config)
+    /// would, without saving it.
+    pub fn preview_config(&self, env: &str, template: &Value) -> Result<Value> {
+        self.runtime
+            .block_on(self.inner.preview_config(env, template))
+    }
+
+    /// Read a populated configuration along with the source of each value.
+    pub fn explain_config(&self, env: &str) -> Result<Explanation> {
+        self.runtime.block_on(self.inner.explain_config(env))
+    }
+
+    /// List all available configuration environment names.
+    pub fn list_configs(&self) -> Result<Vec<String>> {
+        self.runtime.block_on(self.inner.list_configs())
+    }
+
+    /// List the environments and namespaces directly inside a namespace.
+    ///
+    /// An empty namespace lists the top level of the hierarchy.
+    pub fn list_namespace(&self, namespace: &str) -> Result<Listing> {
+        self.runtime.block_on(self.inner.list_namespace(namespace))
+    }
+}
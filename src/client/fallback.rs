@@ -0,0 +1,160 @@
+//! Cache of loaded configs, used when the server is unavailable.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::server::name::EnvName;
+use crate::server::storage::TemplateStore;
+
+/// How long an unchanged config may go without being saved again, which bounds
+/// how much older than its last fetch a cached config may appear.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Configs loaded by a client, kept in a store under a prefix unique to its
+/// origins and secret, so that clients may share a store.
+pub(crate) struct FallbackCache {
+    store: Arc<dyn TemplateStore>,
+    prefix: String,
+    saved: Mutex<HashMap<String, (Value, Instant)>>,
+}
+
+/// Entry in the fallback cache of a client.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    fetched_at: SystemTime,
+    config: Value,
+}
+
+impl FallbackCache {
+    pub fn new(store: Arc<dyn TemplateStore>, origins: &[String], secret: &str) -> Self {
+        let mut origins = origins.to_vec();
+        origins.sort_unstable();
+        let mut hasher = Sha256::new();
+        for origin in &origins {
+            hasher.update(origin.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(secret.as_bytes());
+        let prefix = format!("{:x}", hasher.finalize())[..16].into();
+        Self {
+            store,
+            prefix,
+            saved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn name(&self, env: &str) -> Result<EnvName> {
+        format!("{}/{env}", self.prefix).parse()
+    }
+
+    /// Save a config that was just loaded, unless this cache recently saved
+    /// the same value.
+    pub async fn save(&self, env: &str, config: &Value) {
+        if let Some((saved, at)) = self.saved.lock().get(env) {
+            if saved == config && at.elapsed() < REFRESH_INTERVAL {
+                return;
+            }
+        }
+        let now = Instant::now();
+        let result = async {
+            let entry = CacheEntry {
+                fetched_at: SystemTime::now(),
+                config: config.clone(),
+            };
+            self.store
+                .set(&self.name(env)?, &serde_json::to_value(&entry)?)
+                .await
+        };
+        match result.await {
+            Ok(()) => {
+                self.saved.lock().insert(env.into(), (config.clone(), now));
+            }
+            Err(err) => warn!(%env, ?err, "could not write fallback cache"),
+        }
+    }
+
+    /// Load a cached config, along with the time since it was last fetched.
+    pub async fn load(&self, env: &str) -> Result<(Value, Duration)> {
+        let value = self.store.get(&self.name(env)?).await?;
+        let entry: CacheEntry = serde_json::from_value(value)?;
+        let age = entry.fetched_at.elapsed().unwrap_or_default();
+        Ok((entry.config, age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use anyhow::Result;
+    use serde_json::json;
+    use tokio::time::{self, Duration};
+
+    use super::{CacheEntry, FallbackCache, REFRESH_INTERVAL};
+    use crate::server::storage::{MemoryStore, TemplateStore};
+
+    #[tokio::test]
+    async fn scoped_entries() -> Result<()> {
+        let store = Arc::new(MemoryStore::new());
+        let origins = ["http://a".to_owned(), "http://b".to_owned()];
+        let cache = FallbackCache::new(store.clone(), &origins, "secret");
+        let other = FallbackCache::new(store.clone(), &origins[..1], "secret");
+
+        cache.save("app", &json!(1)).await;
+        other.save("app", &json!(2)).await;
+        assert_eq!(cache.load("app").await?.0, json!(1));
+        assert_eq!(other.load("app").await?.0, json!(2));
+        assert!(cache.load("missing").await.is_err());
+
+        // Unchanged configs are not written again.
+        let name = cache.name("app")?;
+        store.delete(&name).await?;
+        cache.save("app", &json!(1)).await;
+        assert!(cache.load("app").await.is_err());
+        cache.save("app", &json!(3)).await;
+        assert_eq!(cache.load("app").await?.0, json!(3));
+
+        store.set(&name, &json!("corrupt")).await?;
+        assert!(cache.load("app").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refreshed_entries() -> Result<()> {
+        let store = Arc::new(MemoryStore::new());
+        let cache = FallbackCache::new(store.clone(), &["http://a".to_owned()], "secret");
+        let hour = Duration::from_secs(3600);
+
+        // Pretend that the config was first fetched long ago.
+        cache.save("app", &json!(1)).await;
+        let entry = CacheEntry {
+            fetched_at: SystemTime::now() - hour,
+            config: json!(1),
+        };
+        store
+            .set(&cache.name("app")?, &serde_json::to_value(&entry)?)
+            .await?;
+
+        // The fetch time of an unchanged config is only saved again once the
+        // saved one is older than the refresh interval.
+        time::advance(REFRESH_INTERVAL / 2).await;
+        cache.save("app", &json!(1)).await;
+        assert!(cache.load("app").await?.1 >= hour);
+        time::advance(REFRESH_INTERVAL).await;
+        cache.save("app", &json!(1)).await;
+        assert!(cache.load("app").await?.1 < REFRESH_INTERVAL);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Choosing between the origins of several cadre replicas.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use parking_lot::Mutex;
+use tokio::time::Duration;
+
+/// Weight of each new sample in the moving average of an origin's latency.
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// Strategy for choosing which origin serves each request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balance {
+    /// Cycle through healthy origins in turn.
+    RoundRobin,
+
+    /// Prefer the healthy origin with the lowest recent latency. Origins that
+    /// have not been measured yet are tried first.
+    Latency,
+}
+
+/// A list of origins, tracking the health and latency of each.
+pub(crate) struct Origins {
+    origins: Vec<Origin>,
+    balance: Balance,
+    next: AtomicUsize,
+}
+
+struct Origin {
+    url: String,
+    healthy: AtomicBool,
+    latency: Mutex<Option<Duration>>,
+}
+
+impl Origins {
+    pub fn new(urls: Vec<String>, balance: Balance) -> Self {
+        let origins = urls.into_iter().map(|url| Origin {
+            url,
+            healthy: AtomicBool::new(true),
+            latency: Mutex::new(None),
+        });
+        Self {
+            origins: origins.collect(),
+            balance,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.origins.len()
+    }
+
+    pub fn url(&self, index: usize) -> &str {
+        &self.origins[index].url
+    }
+
+    /// Return the order in which to try origins for a request.
+    ///
+    /// Healthy origins come first, ordered by the balancing strategy. Ejected
+    /// origins follow as a last resort, in case every origin is down.
+    pub fn order(&self) -> Vec<usize> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut order: Vec<usize> = (0..self.len()).map(|i| (start + i) % self.len()).collect();
+        match self.balance {
+            Balance::RoundRobin => order.sort_by_key(|&i| !self.is_healthy(i)),
+            Balance::Latency => order.sort_by_key(|&i| {
+                let latency = *self.origins[i].latency.lock();
+                (!self.is_healthy(i), latency.unwrap_or_default())
+            }),
+        }
+        order
+    }
+
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.origins[index].healthy.load(Ordering::Relaxed)
+    }
+
+    /// Mark an origin as unhealthy, returning true if it was healthy before.
+    pub fn eject(&self, index: usize) -> bool {
+        self.origins[index].healthy.swap(false, Ordering::Relaxed)
+    }
+
+    /// Mark an origin as healthy again.
+    pub fn restore(&self, index: usize) {
+        self.origins[index].healthy.store(true, Ordering::Relaxed);
+    }
+
+    /// Record the latency of a successful request to an origin.
+    pub fn record_latency(&self, index: usize, sample: Duration) {
+        let mut latency = self.origins[index].latency.lock();
+        *latency = Some(match *latency {
+            Some(average) => average.mul_f64(1.0 - LATENCY_WEIGHT) + sample.mul_f64(LATENCY_WEIGHT),
+            None => sample,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Duration;
+
+    use super::{Balance, Origins};
+
+    fn origins(balance: Balance) -> Origins {
+        let urls = ["a", "b", "c"].map(String::from);
+        Origins::new(urls.to_vec(), balance)
+    }
+
+    #[test]
+    fn round_robin() {
+        let origins = origins(Balance::RoundRobin);
+        assert_eq!(origins.order(), [0, 1, 2]);
+        assert_eq!(origins.order(), [1, 2, 0]);
+
+        // Ejected origins are only tried last, until they are restored.
+        assert!(origins.eject(0));
+        assert!(!origins.eject(0));
+        assert_eq!(origins.order(), [2, 1, 0]);
+        assert_eq!(origins.order(), [1, 2, 0]);
+        origins.restore(0);
+        assert_eq!(origins.order(), [1, 2, 0]);
+        assert_eq!(origins.order(), [2, 0, 1]);
+    }
+
+    #[test]
+    fn lowest_latency() {
+        let origins = origins(Balance::Latency);
+        origins.record_latency(0, Duration::from_millis(30));
+        origins.record_latency(1, Duration::from_millis(10));
+        assert_eq!(origins.order()[..2], [2, 1]);
+        origins.record_latency(2, Duration::from_millis(20));
+        assert_eq!(origins.order(), [1, 2, 0]);
+
+        // Latency is averaged over recent requests.
+        origins.record_latency(1, Duration::from_millis(100));
+        assert_eq!(origins.order(), [2, 0, 1]);
+        origins.eject(2);
+        assert_eq!(origins.order(), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,114 @@
+//! TLS configuration for connecting to cadre over HTTPS.
+
+use anyhow::{ensure, Context, Result};
+use hyper::client::HttpConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+use tracing::warn;
+
+use crate::pem::{parse_certificates, parse_private_key};
+
+/// Settings for verifying servers and authenticating to them over TLS.
+///
+/// By default, servers are verified against the platform's native root
+/// certificates, and no client certificate is presented.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    native_roots: bool,
+    roots: Vec<Certificate>,
+    identity: Option<(Vec<Certificate>, PrivateKey)>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            native_roots: true,
+            roots: Vec::new(),
+            identity: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Create a configuration trusting the platform's native root
+    /// certificates.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stop trusting the platform's native root certificates, so that only
+    /// roots added with [`add_root_certificates`](Self::add_root_certificates)
+    /// are trusted.
+    pub fn without_native_roots(mut self) -> Self {
+        self.native_roots = false;
+        self
+    }
+
+    /// Trust every certificate in a PEM file as a root, such as a private CA.
+    pub fn add_root_certificates(mut self, pem: &[u8]) -> Result<Self> {
+        let certs = parse_certificates(pem)?;
+        ensure!(!certs.is_empty(), "no root certificates found in PEM");
+        self.roots.extend(certs);
+        Ok(self)
+    }
+
+    /// Present a client certificate for mutual TLS, given PEM files with the
+    /// certificate chain and its private key.
+    pub fn identity(mut self, cert_pem: &[u8], key_pem: &[u8]) -> Result<Self> {
+        let certs = parse_certificates(cert_pem)?;
+        ensure!(!certs.is_empty(), "no client certificates found in PEM");
+        self.identity = Some((certs, parse_private_key(key_pem)?));
+        Ok(self)
+    }
+
+    /// Build a connector that speaks both HTTP and HTTPS with these settings.
+    pub(crate) fn connector(&self, http: HttpConnector) -> Result<HttpsConnector<HttpConnector>> {
+        let mut roots = RootCertStore::empty();
+        if self.native_roots {
+            match rustls_native_certs::load_native_certs() {
+                Ok(certs) => {
+                    let certs: Vec<_> = certs.into_iter().map(|cert| cert.0).collect();
+                    roots.add_parsable_certificates(&certs);
+                }
+                Err(err) => warn!(?err, "could not load native root certificates"),
+            }
+        }
+        for cert in &self.roots {
+            roots.add(cert).context("invalid root certificate")?;
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match &self.identity {
+            Some((certs, key)) => builder
+                .with_single_cert(certs.clone(), key.clone())
+                .context("invalid client certificate")?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(HttpsConnectorBuilder::new()
+            .with_tls_config(config)
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::client::HttpConnector;
+
+    use super::TlsConfig;
+
+    #[test]
+    fn invalid_pem_files() {
+        assert!(TlsConfig::new().connector(HttpConnector::new()).is_ok());
+        assert!(TlsConfig::new().add_root_certificates(b"").is_err());
+        assert!(TlsConfig::new()
+            .add_root_certificates(b"-----BEGIN CERTIFICATE-----\nbad")
+            .is_err());
+        assert!(TlsConfig::new().identity(b"", b"").is_err());
+    }
+}
@@ -6,7 +6,9 @@
 
 pub mod cli;
 pub mod client;
+#[cfg(feature = "tls")]
+mod pem;
 pub mod server;
 
 pub use crate::cli::Args;
-pub use crate::client::CadreClient;
+pub use crate::client::{CadreClient, ClientBuilder, LoadedConfig};
@@ -0,0 +1,24 @@
+//! Parsing of PEM files with certificates and private keys, for TLS.
+
+use anyhow::{bail, Context, Result};
+use rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+
+/// Parse every certificate in a PEM file.
+pub(crate) fn parse_certificates(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut &*pem).context("invalid certificate PEM")?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Parse the first private key in a PEM file.
+pub(crate) fn parse_private_key(pem: &[u8]) -> Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut &*pem).context("invalid private key PEM")? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+            _ => (),
+        }
+    }
+    bail!("no private key found in PEM")
+}
@@ -3,61 +3,197 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use anyhow::Result;
-use axum::extract::{Extension, Path};
-use axum::http::{Request, StatusCode};
+use async_trait::async_trait;
+use axum::extract::{Extension, FromRequest, Path, Query, RequestParts};
+use axum::http::{header, HeaderMap, Request, StatusCode};
 use axum::middleware;
-use axum::response::Response;
-use axum::routing::get;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
 use axum::{response::Html, Json, Router};
+use serde::Deserialize;
 use serde_json::Value;
 use tracing::{error, warn};
 
-use self::state::State;
+use self::auth::{Access, ClientIdentity, Credentials};
+use self::name::EnvName;
+use self::schema::{is_schema_name, SchemaViolation, SCHEMA_SUFFIX};
+use self::state::{Explanation, Listing, State};
+use self::storage::{Revision, Unsupported, VersionConflict};
+use self::template::PopulateFailure;
 
+pub mod auth;
 pub mod cache;
+pub mod crypto;
+#[cfg(feature = "git")]
+pub mod git;
+pub mod layered;
+pub mod mirror;
+pub mod name;
 pub mod resolver;
+pub mod schema;
+#[cfg(feature = "sql")]
+pub mod sql;
 pub mod state;
 pub mod storage;
 pub mod template;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 /// Authorization token validation.
+///
+/// On success, this records the [`Access`] granted to the client as a request
+/// extension, for handlers to check against the environments they touch.
 async fn auth<B>(
-    req: Request<B>,
+    mut req: Request<B>,
     next: middleware::Next<B>,
-    secret: String,
+    credentials: Arc<Credentials>,
 ) -> Result<Response, StatusCode> {
-    let auth_header = req
+    let access = req
         .headers()
         .get("X-Cadre-Secret")
-        .and_then(|header| header.to_str().ok());
+        .and_then(|header| header.to_str().ok())
+        .and_then(|secret| credentials.authenticate(secret))
+        .or_else(|| {
+            // Fall back to an identity verified by the connection, if any.
+            let identity = req.extensions().get::<ClientIdentity>()?;
+            credentials.identify(identity)
+        });
 
-    // Checks auth header against secret.
-    match auth_header {
-        Some(auth_header) if auth_header == secret => Ok(next.run(req).await),
-        _ => Err(StatusCode::UNAUTHORIZED),
+    // Checks auth header against the known secrets.
+    match access {
+        Some(access) => {
+            req.extensions_mut().insert(access);
+            Ok(next.run(req).await)
+        }
+        None => Err(StatusCode::UNAUTHORIZED),
     }
 }
 
 /// Web server for handling requests.
-pub fn server(state: State, secret: String) -> Router {
+pub fn server(state: State, credentials: impl Into<Credentials>) -> Router {
+    let credentials = Arc::new(credentials.into());
     Router::new()
-        .route("/t/:env", get(get_template_handler).put(put_handler))
+        .route(
+            "/t/*env",
+            get(get_template_handler)
+                .put(put_handler)
+                .delete(delete_handler),
+        )
+        .route(
+            "/s/*env",
+            get(get_schema_handler)
+                .put(put_schema_handler)
+                .delete(delete_schema_handler),
+        )
+        .route("/preview/*env", post(preview_handler))
+        .route("/explain/*env", get(explain_handler))
         .route("/c", get(list_configs_handler))
-        .route("/c/:env", get(get_config_handler))
+        .route("/c/*env", get(get_config_handler))
+        .route("/n/*namespace", get(list_namespace_handler))
+        .route("/h/*env", get(history_handler))
         .layer(Extension(state))
         .route_layer(middleware::from_fn(move |req, next| {
-            auth(req, next, secret.clone())
+            auth(req, next, Arc::clone(&credentials))
         }))
         .route("/ping", get(|| async { "cadre ok" }))
         .route("/", get(|| async { Html(include_str!("index.html")) }))
 }
 
+/// Extractor for an environment name spanning the rest of the request path.
+struct EnvPath(EnvName);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for EnvPath {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Path(path) = Path::<String>::from_request(req)
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        Ok(Self(parse_env(path.trim_start_matches('/'))?))
+    }
+}
+
+/// Parse an environment name given by a client.
+fn parse_env(name: &str) -> Result<EnvName, (StatusCode, String)> {
+    let env: EnvName = match name.parse() {
+        Ok(env) => env,
+        Err(err) => return Err((StatusCode::BAD_REQUEST, format!("{err}"))),
+    };
+    if is_schema_name(&env) {
+        let message = format!("environment name {env:?} ends with {SCHEMA_SUFFIX:?}");
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    Ok(env)
+}
+
+/// Respond with every problem found if an error was caused by an invalid
+/// template, such as one that does not match its schemas.
+///
+/// If `redact` is true, schema violations are reported without their messages,
+/// which may quote secret values.
+fn invalid_template(err: &anyhow::Error, redact: bool) -> Option<Response> {
+    let body = if let Some(violation) = err.downcast_ref::<SchemaViolation>() {
+        match redact {
+            true => Json(violation.redacted()).into_response(),
+            false => Json(violation).into_response(),
+        }
+    } else if let Some(failure) = err.downcast_ref::<PopulateFailure>() {
+        Json(failure).into_response()
+    } else {
+        return None;
+    };
+    Some((StatusCode::UNPROCESSABLE_ENTITY, body).into_response())
+}
+
+/// Reject requests for environments that the client cannot access.
+fn check_access(access: &Access, env: &EnvName) -> Result<(), StatusCode> {
+    if access.allows(env) {
+        Ok(())
+    } else {
+        warn!(%env, ?access, "forbidden access to environment");
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+/// Reject writes from clients that cannot access an environment, or that may
+/// only read it with secrets redacted.
+///
+/// Redacted clients must not change templates or schemas, since a crafted
+/// template or schema could reveal secret values through its side effects.
+fn check_write_access(access: &Access, env: &EnvName) -> Result<(), StatusCode> {
+    check_access(access, env)?;
+    if access.reveals_secrets() {
+        Ok(())
+    } else {
+        warn!(%env, ?access, "forbidden write from read-only client");
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+/// Query parameters for reading a template.
+#[derive(Deserialize)]
+struct TemplateQuery {
+    /// Past revision of the template to read, instead of the current one.
+    revision: Option<String>,
+}
+
 async fn get_template_handler(
     Extension(state): Extension<State>,
-    Path(env): Path<String>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+    Query(query): Query<TemplateQuery>,
 ) -> Result<Json<Value>, StatusCode> {
-    match state.read_template(&env).await {
+    check_access(&access, &env)?;
+    let result = match &query.revision {
+        Some(id) => state.read_template_revision(&env, id).await,
+        None => state.read_template(&env).await,
+    };
+    match result {
         Ok(value) => Ok(Json(value)),
         Err(err) => {
             warn!(%env, ?err, "problem getting template");
@@ -66,24 +202,164 @@ async fn get_template_handler(
     }
 }
 
+/// Query parameters for reading a populated config.
+#[derive(Deserialize)]
+struct ConfigQuery {
+    /// Redact secret values, even if the client is allowed to read them.
+    #[serde(default)]
+    redact: bool,
+}
+
+impl ConfigQuery {
+    /// Return true if secret values should be hidden from the client.
+    fn redacts(&self, access: &Access) -> bool {
+        self.redact || !access.reveals_secrets()
+    }
+}
+
 async fn get_config_handler(
     Extension(state): Extension<State>,
-    Path(env): Path<String>,
-) -> Result<Json<Value>, StatusCode> {
-    match state.load_config(&env).await {
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+    Query(query): Query<ConfigQuery>,
+) -> Result<Json<Value>, Response> {
+    check_access(&access, &env).map_err(IntoResponse::into_response)?;
+    let redact = query.redacts(&access);
+    let result = match redact {
+        true => state.load_redacted_config(&env).await,
+        false => state.load_config(&env).await,
+    };
+    match result {
         Ok(value) => Ok(Json(value)),
         Err(err) => {
             warn!(%env, ?err, "problem reading config");
+            // Resolver failures are only detailed when previewing a template.
+            let response = match err.is::<PopulateFailure>() {
+                true => None,
+                false => invalid_template(&err, redact),
+            };
+            Err(response.unwrap_or_else(|| StatusCode::NOT_FOUND.into_response()))
+        }
+    }
+}
+
+async fn preview_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+    Query(query): Query<ConfigQuery>,
+    body: Json<Value>,
+) -> Result<Json<Value>, Response> {
+    check_access(&access, &env).map_err(IntoResponse::into_response)?;
+    let redact = query.redacts(&access);
+    match state.preview_config(&env, &body, redact).await {
+        Ok(value) => Ok(Json(value)),
+        Err(err) => {
+            warn!(%env, ?err, "problem previewing config");
+            let response = invalid_template(&err, redact);
+            Err(response.unwrap_or_else(|| StatusCode::NOT_FOUND.into_response()))
+        }
+    }
+}
+
+async fn explain_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+    Query(query): Query<ConfigQuery>,
+) -> Result<Json<Explanation>, Response> {
+    check_access(&access, &env).map_err(IntoResponse::into_response)?;
+    let redact = query.redacts(&access);
+    match state.explain_config(&env, redact).await {
+        Ok(value) => Ok(Json(value)),
+        Err(err) => {
+            warn!(%env, ?err, "problem explaining config");
+            let response = invalid_template(&err, redact);
+            Err(response.unwrap_or_else(|| StatusCode::NOT_FOUND.into_response()))
+        }
+    }
+}
+
+async fn history_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+) -> Result<Json<Vec<Revision>>, StatusCode> {
+    check_access(&access, &env)?;
+    match state.template_history(&env).await {
+        Ok(value) => Ok(Json(value)),
+        Err(err) => {
+            warn!(%env, ?err, "problem reading template history");
             Err(StatusCode::NOT_FOUND)
         }
     }
 }
 
+/// Query parameters for listing configs, or reading several at once.
+#[derive(Deserialize)]
+struct ConfigsQuery {
+    /// Comma-separated environments to read, instead of listing them all.
+    envs: Option<String>,
+
+    /// Redact secret values, even if the client is allowed to read them.
+    #[serde(default)]
+    redact: bool,
+}
+
 async fn list_configs_handler(
+    state: Extension<State>,
+    access: Extension<Access>,
+    Query(query): Query<ConfigsQuery>,
+) -> Response {
+    match &query.envs {
+        Some(envs) => get_configs(
+            state,
+            access,
+            envs,
+            &ConfigQuery {
+                redact: query.redact,
+            },
+        )
+        .await
+        .map(Json)
+        .into_response(),
+        None => list_configs(state, access).await.into_response(),
+    }
+}
+
+/// Read several populated configs, keyed by environment.
+async fn get_configs(
     Extension(state): Extension<State>,
-) -> Result<Json<Vec<String>>, StatusCode> {
+    Extension(access): Extension<Access>,
+    envs: &str,
+    query: &ConfigQuery,
+) -> Result<BTreeMap<EnvName, Value>, Response> {
+    let mut names = Vec::new();
+    for name in envs.split(',').filter(|name| !name.is_empty()) {
+        let env = parse_env(name).map_err(IntoResponse::into_response)?;
+        check_access(&access, &env).map_err(IntoResponse::into_response)?;
+        names.push(env);
+    }
+    let redact = query.redacts(&access);
+    state.load_configs(&names, redact).await.map_err(|err| {
+        warn!(%envs, ?err, "problem reading configs");
+        let response = match err.is::<PopulateFailure>() {
+            true => None,
+            false => invalid_template(&err, redact),
+        };
+        response.unwrap_or_else(|| StatusCode::NOT_FOUND.into_response())
+    })
+}
+
+async fn list_configs(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<Vec<EnvName>>, StatusCode> {
     match state.list_configs().await {
-        Ok(value) => Ok(Json(value)),
+        Ok(mut value) => {
+            value.retain(|env| access.allows(env));
+            Ok(Json(value))
+        }
         Err(err) => {
             warn!(?err, "problem reading all configs");
             Err(StatusCode::NOT_FOUND)
@@ -91,15 +367,146 @@ async fn list_configs_handler(
     }
 }
 
+async fn list_namespace_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Listing>, StatusCode> {
+    let namespace = match namespace.trim_start_matches('/') {
+        "" => None,
+        namespace => Some(namespace.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+    };
+    match state.list_namespace(namespace.as_ref()).await {
+        Ok(mut value) => {
+            value.namespaces.retain(|ns| access.allows_namespace(ns));
+            value.configs.retain(|env| access.allows(env));
+            Ok(Json(value))
+        }
+        Err(err) => {
+            warn!(?namespace, ?err, "problem listing namespace");
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
 async fn put_handler(
     Extension(state): Extension<State>,
-    Path(env): Path<String>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+    headers: HeaderMap,
     body: Json<Value>,
+) -> Result<(), Response> {
+    check_write_access(&access, &env).map_err(IntoResponse::into_response)?;
+    // An `If-Match` header makes the write conditional on the current version.
+    let expected = match headers.get(header::IF_MATCH) {
+        Some(value) => {
+            let value = value
+                .to_str()
+                .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+            Some(value.trim_matches('"').to_owned())
+        }
+        None => None,
+    };
+    let result = match &expected {
+        Some(expected) => state.write_template_if(&env, &body, expected).await,
+        None => state.write_template(&env, &body).await,
+    };
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) if err.is::<VersionConflict>() => {
+            warn!(%env, ?err, "conflicting write to template");
+            Err(StatusCode::PRECONDITION_FAILED.into_response())
+        }
+        Err(err) if err.is::<Unsupported>() => {
+            Err((StatusCode::NOT_IMPLEMENTED, err.to_string()).into_response())
+        }
+        Err(err) => match invalid_template(&err, false) {
+            Some(response) => {
+                warn!(%env, ?err, "template does not match schema");
+                Err(response)
+            }
+            None => {
+                error!(?err, "could not put config");
+                Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+        },
+    }
+}
+
+async fn delete_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+) -> Result<(), StatusCode> {
+    check_write_access(&access, &env)?;
+    match state.delete_template(&env).await {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            error!(%env, ?err, "could not delete template");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Query parameters for reading a schema.
+#[derive(Deserialize)]
+struct SchemaQuery {
+    /// Combine every schema that applies to the environment, including those
+    /// of its enclosing namespaces.
+    #[serde(default)]
+    effective: bool,
+}
+
+async fn get_schema_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+    Query(query): Query<SchemaQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    check_access(&access, &env)?;
+    let result = match query.effective {
+        true => state.effective_schema(&env).await,
+        false => state.read_schema(&env).await,
+    };
+    match result {
+        Ok(value) => Ok(Json(value)),
+        Err(err) => {
+            warn!(%env, ?err, "problem getting schema");
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+async fn put_schema_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
+    body: Json<Value>,
+) -> Result<(), Response> {
+    check_write_access(&access, &env).map_err(IntoResponse::into_response)?;
+    match state.write_schema(&env, &body).await {
+        Ok(_) => Ok(()),
+        Err(err) if err.is::<SchemaViolation>() => {
+            warn!(%env, ?err, "invalid schema");
+            Err((StatusCode::BAD_REQUEST, err.to_string()).into_response())
+        }
+        Err(err) => {
+            error!(%env, ?err, "could not put schema");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn delete_schema_handler(
+    Extension(state): Extension<State>,
+    Extension(access): Extension<Access>,
+    EnvPath(env): EnvPath,
 ) -> Result<(), StatusCode> {
-    match state.write_template(&env, &body).await {
+    check_write_access(&access, &env)?;
+    match state.delete_schema(&env).await {
         Ok(_) => Ok(()),
         Err(err) => {
-            error!(?err, "could not put config");
+            error!(%env, ?err, "could not delete schema");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
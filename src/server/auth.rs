@@ -0,0 +1,175 @@
+//! Credentials and access control for clients of the web server.
+
+use super::name::EnvName;
+
+/// Secrets that clients may present to authenticate with the server.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    secret: String,
+    scoped: Vec<(String, Access)>,
+    identities: Vec<(String, Access)>,
+}
+
+/// Identity of a client, verified by other means than a secret, such as the
+/// common name of a TLS client certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientIdentity(pub String);
+
+/// What an authenticated client may access.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Access {
+    namespace: Option<EnvName>,
+    secrets: bool,
+}
+
+impl Credentials {
+    /// Create credentials with a single secret granting access to everything.
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: secret.into(),
+            scoped: Vec::new(),
+            identities: Vec::new(),
+        }
+    }
+
+    /// Add a secret granting access only to environments in a namespace.
+    pub fn add_namespace(&mut self, namespace: EnvName, secret: &str) {
+        self.add_secret(secret, Access::namespace(namespace));
+    }
+
+    /// Add a secret granting some limited access.
+    pub fn add_secret(&mut self, secret: &str, access: Access) {
+        self.scoped.push((secret.into(), access));
+    }
+
+    /// Grant access to clients with a verified identity.
+    pub fn add_identity(&mut self, identity: &str, access: Access) {
+        self.identities.push((identity.into(), access));
+    }
+
+    /// Check a verified identity, returning what it can access.
+    pub fn identify(&self, identity: &ClientIdentity) -> Option<Access> {
+        self.identities
+            .iter()
+            .find(|(name, _)| *name == identity.0)
+            .map(|(_, access)| access.clone())
+    }
+
+    /// Check a secret presented by a client, returning what it can access.
+    pub fn authenticate(&self, secret: &str) -> Option<Access> {
+        if secret == self.secret {
+            return Some(Access::all());
+        }
+        self.scoped
+            .iter()
+            .find(|(scoped_secret, _)| secret == scoped_secret)
+            .map(|(_, access)| access.clone())
+    }
+}
+
+impl From<String> for Credentials {
+    fn from(secret: String) -> Self {
+        Self::new(&secret)
+    }
+}
+
+impl Access {
+    /// Access to every environment, including secret values.
+    pub fn all() -> Self {
+        Self {
+            namespace: None,
+            secrets: true,
+        }
+    }
+
+    /// Access only to environments nested under a namespace, including secret
+    /// values.
+    pub fn namespace(namespace: EnvName) -> Self {
+        Self {
+            namespace: Some(namespace),
+            secrets: true,
+        }
+    }
+
+    /// Remove the scope for reading secret values, so that populated configs
+    /// are served with secrets redacted.
+    pub fn redacted(self) -> Self {
+        Self {
+            secrets: false,
+            ..self
+        }
+    }
+
+    /// Return true if this grants access to secret values in configs.
+    pub fn reveals_secrets(&self) -> bool {
+        self.secrets
+    }
+
+    /// Return true if this grants access to an environment.
+    pub fn allows(&self, env: &EnvName) -> bool {
+        match &self.namespace {
+            None => true,
+            Some(namespace) => env.is_within(namespace),
+        }
+    }
+
+    /// Return true if a namespace contains anything this grants access to.
+    pub fn allows_namespace(&self, namespace: &EnvName) -> bool {
+        match &self.namespace {
+            None => true,
+            Some(allowed) => {
+                namespace == allowed || namespace.is_within(allowed) || allowed.is_within(namespace)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, ClientIdentity, Credentials};
+
+    #[test]
+    fn namespace_access() {
+        let name = |s: &str| s.parse().unwrap();
+        let mut credentials = Credentials::new("root");
+        credentials.add_namespace(name("team"), "team-secret");
+
+        assert_eq!(credentials.authenticate("root"), Some(Access::all()));
+        assert_eq!(credentials.authenticate("wrong"), None);
+
+        let access = credentials.authenticate("team-secret").unwrap();
+        assert!(access.allows(&name("team/prod")));
+        assert!(access.allows(&name("team/service/prod")));
+        assert!(!access.allows(&name("team")));
+        assert!(!access.allows(&name("teams/prod")));
+        assert!(!access.allows(&name("default")));
+
+        assert!(access.allows_namespace(&name("team")));
+        assert!(access.allows_namespace(&name("team/service")));
+        assert!(!access.allows_namespace(&name("other")));
+        assert!(access.reveals_secrets());
+    }
+
+    #[test]
+    fn redacted_access() {
+        let mut credentials = Credentials::new("root");
+        credentials.add_secret("viewer", Access::all().redacted());
+
+        assert!(credentials.authenticate("root").unwrap().reveals_secrets());
+        let access = credentials.authenticate("viewer").unwrap();
+        assert!(!access.reveals_secrets());
+        assert!(access.allows(&"prod".parse().unwrap()));
+    }
+
+    #[test]
+    fn identity_access() {
+        let mut credentials = Credentials::new("root");
+        let team = Access::namespace("team".parse().unwrap());
+        credentials.add_identity("deployer", team.clone());
+
+        let identity = |name: &str| ClientIdentity(name.into());
+        assert_eq!(credentials.identify(&identity("deployer")), Some(team));
+        assert_eq!(credentials.identify(&identity("root")), None);
+        assert_eq!(credentials.authenticate("deployer"), None);
+    }
+}
@@ -1,27 +1,77 @@
 //! Implementation of data caching for slow resolvers.
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
+use std::sync::{Arc, Weak};
 
 use parking_lot::Mutex;
-use tokio::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+
+/// Default maximum number of entries held by a [`TimedCache`].
+pub const DEFAULT_CAPACITY: usize = 4096;
 
 /// A timed cache, with random jitter on the time-to-live.
+///
+/// The cache holds at most a fixed number of entries. When it is full, the
+/// least recently used entry is evicted to make room for new ones.
 #[derive(Debug)]
 pub struct TimedCache<K, V> {
-    inner: Mutex<HashMap<K, (Instant, V)>>,
+    inner: Arc<Mutex<Inner<K, V>>>,
     min_ttl: f64,
     max_ttl: f64,
+    capacity: usize,
+}
+
+/// Hit, miss and eviction counters for a [`TimedCache`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of lookups that returned a live entry.
+    pub hits: u64,
+    /// Number of lookups that found no entry, or an expired one.
+    pub misses: u64,
+    /// Number of entries removed to stay within capacity.
+    pub evictions: u64,
+    /// Number of expired entries removed from the cache.
+    pub expirations: u64,
+}
+
+#[derive(Debug)]
+struct Inner<K, V> {
+    map: HashMap<K, Entry<V>>,
+    /// Keys ordered by last access, oldest first.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    expire_time: Instant,
+    tick: u64,
+    value: V,
 }
 
-impl<K: Eq + Hash, V: Clone> TimedCache<K, V> {
+impl<K: Eq + Hash + Clone, V: Clone> TimedCache<K, V> {
     /// Create a new timed cache with min and max TTLs.
     pub fn new(min_ttl: f64, max_ttl: f64) -> Self {
+        Self::with_capacity(min_ttl, max_ttl, DEFAULT_CAPACITY)
+    }
+
+    /// Create a new timed cache holding at most `capacity` entries.
+    pub fn with_capacity(min_ttl: f64, max_ttl: f64, capacity: usize) -> Self {
+        assert!(capacity > 0, "cache capacity must be positive");
         Self {
-            inner: Default::default(),
+            inner: Arc::new(Mutex::new(Inner {
+                map: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            })),
             min_ttl,
             max_ttl,
+            capacity,
         }
     }
 
@@ -32,12 +82,31 @@ impl<K: Eq + Hash, V: Clone> TimedCache<K, V> {
         Q: Hash + Eq + ?Sized,
     {
         let mut inner = self.inner.lock();
-        let (expire_time, value) = inner.get(k)?;
-        if *expire_time >= Instant::now() {
-            Some(value.clone())
-        } else {
-            inner.remove(k);
-            None
+        let inner = &mut *inner;
+        let now = Instant::now();
+        match inner.map.get_mut(k) {
+            Some(entry) if entry.expire_time >= now => {
+                inner.tick += 1;
+                let key = inner
+                    .order
+                    .remove(&entry.tick)
+                    .expect("entry missing from order");
+                entry.tick = inner.tick;
+                inner.order.insert(entry.tick, key);
+                inner.stats.hits += 1;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                let entry = inner.map.remove(k).unwrap();
+                inner.order.remove(&entry.tick);
+                inner.stats.misses += 1;
+                inner.stats.expirations += 1;
+                None
+            }
+            None => {
+                inner.stats.misses += 1;
+                None
+            }
         }
     }
 
@@ -45,7 +114,110 @@ impl<K: Eq + Hash, V: Clone> TimedCache<K, V> {
     pub fn insert(&self, k: K, v: V) {
         let ttl = self.min_ttl + fastrand::f64() * (self.max_ttl - self.min_ttl);
         let expire_time = Instant::now() + Duration::from_secs_f64(ttl);
-        self.inner.lock().insert(k, (expire_time, v));
+
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = Entry {
+            expire_time,
+            tick,
+            value: v,
+        };
+        if let Some(old) = inner.map.insert(k.clone(), entry) {
+            inner.order.remove(&old.tick);
+        }
+        inner.order.insert(tick, k);
+
+        while inner.map.len() > self.capacity {
+            let oldest = *inner
+                .order
+                .keys()
+                .next()
+                .expect("order out of sync with map");
+            let key = inner.order.remove(&oldest).unwrap();
+            inner.map.remove(&key);
+            inner.stats.evictions += 1;
+        }
+    }
+
+    /// Remove an entry from the cache, returning its value if it was present.
+    pub fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut inner = self.inner.lock();
+        let entry = inner.map.remove(k)?;
+        inner.order.remove(&entry.tick);
+        Some(entry.value)
+    }
+
+    /// Remove all entries from the cache.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.map.clear();
+        inner.order.clear();
+    }
+
+    /// Remove all expired entries from the cache.
+    pub fn sweep(&self) {
+        self.inner.lock().sweep(Instant::now());
+    }
+
+    /// Return the number of entries currently held, including expired ones
+    /// that have not been swept yet.
+    pub fn len(&self) -> usize {
+        self.inner.lock().map.len()
+    }
+
+    /// Return true if the cache holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return a snapshot of the cache's hit, miss and eviction counters.
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats
+    }
+}
+
+impl<K, V> TimedCache<K, V>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Send + 'static,
+{
+    /// Spawn a background task that sweeps expired entries every `period`.
+    ///
+    /// The task exits on its own once the cache is dropped. This must be
+    /// called from within a Tokio runtime.
+    pub fn spawn_sweeper(&self, period: Duration) -> JoinHandle<()> {
+        let inner: Weak<Mutex<Inner<K, V>>> = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match inner.upgrade() {
+                    Some(inner) => inner.lock().sweep(Instant::now()),
+                    None => break,
+                }
+            }
+        })
+    }
+}
+
+impl<K: Eq + Hash, V> Inner<K, V> {
+    fn sweep(&mut self, now: Instant) {
+        let before = self.map.len();
+        let order = &mut self.order;
+        self.map.retain(|_, entry| {
+            let live = entry.expire_time >= now;
+            if !live {
+                order.remove(&entry.tick);
+            }
+            live
+        });
+        self.stats.expirations += (before - self.map.len()) as u64;
     }
 }
 
@@ -53,7 +225,7 @@ impl<K: Eq + Hash, V: Clone> TimedCache<K, V> {
 mod tests {
     use tokio::time::{self, Duration};
 
-    use super::TimedCache;
+    use super::{CacheStats, TimedCache};
 
     #[tokio::test(start_paused = true)]
     async fn timed_expire() {
@@ -66,4 +238,52 @@ mod tests {
         time::advance(Duration::from_secs(10)).await;
         assert_eq!(cache.get("foo"), None);
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn lru_eviction() {
+        let cache = TimedCache::with_capacity(60.0, 60.0, 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+
+        // Touch "a" so that "b" becomes the least recently used entry.
+        assert_eq!(cache.get("a"), Some(1));
+        cache.insert("c", 3);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("c"), Some(3));
+
+        // Overwriting an existing key does not evict anything.
+        cache.insert("c", 4);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 1,
+                evictions: 1,
+                expirations: 0,
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn background_sweep() {
+        let cache = TimedCache::new(5.0, 5.0);
+        let handle = cache.spawn_sweeper(Duration::from_secs(1));
+        cache.insert("foo", "bar");
+        cache.insert("baz", "qux");
+
+        time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(cache.len(), 2);
+
+        time::sleep(Duration::from_secs(5)).await;
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats().expirations, 2);
+
+        // The sweeper exits once the cache is dropped.
+        drop(cache);
+        handle.await.unwrap();
+    }
 }
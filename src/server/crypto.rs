@@ -0,0 +1,394 @@
+//! Envelope encryption of templates at rest.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::broadcast;
+use tracing::info;
+
+use super::name::EnvName;
+use super::storage::{Revision, TemplateStore};
+
+/// Field that marks a stored value as an encrypted template.
+const ENVELOPE_FIELD: &str = "$cadre_encrypted";
+
+/// Length in bytes of the nonces used by AES-GCM.
+const NONCE_LEN: usize = 12;
+
+/// A template body encrypted with a data key, alongside that data key
+/// encrypted with a key-encryption key.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    /// Identifier of the key-encryption key that wrapped the data key.
+    kek: String,
+
+    /// Encrypted data key, base64-encoded.
+    dek: String,
+
+    /// Nonce used to encrypt the template body, base64-encoded.
+    nonce: String,
+
+    /// Encrypted template body, base64-encoded.
+    data: String,
+}
+
+/// Local key-encryption keys, read from a key file.
+///
+/// Each non-empty line of the file has the form `ID:KEY`, where `KEY` is 32
+/// random bytes encoded as base64. The last key in the file is the active one,
+/// used for new writes, while the others can still decrypt older templates. To
+/// rotate keys, append a new line to the file.
+pub struct Keyring {
+    keys: HashMap<String, Key<Aes256Gcm>>,
+    active: String,
+}
+
+impl Keyring {
+    /// Read a keyring from a key file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read key file {}", path.display()))?;
+        content.parse()
+    }
+
+    /// Generate a new random key, returning it as a line for a key file.
+    pub fn generate(id: &str) -> String {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        format!("{id}:{}", base64::encode(key))
+    }
+
+    /// Derive a key for another purpose from the active key, so that it is
+    /// shared by every replica using the same key file.
+    pub fn derive_key(&self, purpose: &str) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.keys[&self.active])
+            .expect("HMAC accepts keys of any length");
+        mac.update(purpose.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    fn wrap(&self, dek: &[u8]) -> Result<(String, Vec<u8>)> {
+        let cipher = Aes256Gcm::new(&self.keys[&self.active]);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(
+            cipher
+                .encrypt(&nonce, dek)
+                .map_err(|_| anyhow!("could not wrap data key"))?,
+        );
+        Ok((format!("local:{}", self.active), wrapped))
+    }
+
+    fn unwrap(&self, id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        let key = self
+            .keys
+            .get(id)
+            .with_context(|| format!("unknown encryption key {id}"))?;
+        ensure!(wrapped.len() > NONCE_LEN, "wrapped data key is too short");
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+        Aes256Gcm::new(key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("could not unwrap data key with key {id}"))
+    }
+}
+
+impl std::str::FromStr for Keyring {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        let mut active = None;
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (id, key) = line
+                .split_once(':')
+                .context("expected key file lines of the form ID:KEY")?;
+            let key = base64::decode(key).with_context(|| format!("invalid key {id}"))?;
+            ensure!(key.len() == 32, "key {id} must be 32 bytes long");
+            keys.insert(id.to_owned(), *Key::<Aes256Gcm>::from_slice(&key));
+            active = Some(id.to_owned());
+        }
+        Ok(Self {
+            keys,
+            active: active.context("key file is empty")?,
+        })
+    }
+}
+
+/// Generate 32 random bytes for use as a key.
+pub fn random_key() -> [u8; 32] {
+    Aes256Gcm::generate_key(&mut OsRng).into()
+}
+
+/// Source of the key-encryption keys that protect data keys.
+pub enum KeyProvider {
+    /// Keys read from a local key file.
+    Local(Keyring),
+
+    /// A key managed by AWS KMS, identified by its ID, ARN or alias.
+    #[cfg(feature = "kms")]
+    Kms(aws_sdk_kms::Client, String),
+}
+
+impl KeyProvider {
+    /// Identifier recorded on envelopes written with the active key.
+    fn active_id(&self) -> String {
+        match self {
+            KeyProvider::Local(keyring) => format!("local:{}", keyring.active),
+            #[cfg(feature = "kms")]
+            KeyProvider::Kms(_, key_id) => format!("kms:{key_id}"),
+        }
+    }
+
+    /// Encrypt a data key with the active key-encryption key.
+    async fn wrap(&self, dek: &[u8]) -> Result<(String, Vec<u8>)> {
+        match self {
+            KeyProvider::Local(keyring) => keyring.wrap(dek),
+            #[cfg(feature = "kms")]
+            KeyProvider::Kms(client, key_id) => {
+                let resp = client
+                    .encrypt()
+                    .key_id(key_id)
+                    .plaintext(aws_sdk_kms::types::Blob::new(dek))
+                    .send()
+                    .await?;
+                let blob = resp.ciphertext_blob().context("missing KMS ciphertext")?;
+                Ok((self.active_id(), blob.as_ref().to_vec()))
+            }
+        }
+    }
+
+    /// Decrypt a data key that was wrapped by the key with the given ID.
+    async fn unwrap(&self, kek: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        match (self, kek.split_once(':')) {
+            (KeyProvider::Local(keyring), Some(("local", id))) => keyring.unwrap(id, wrapped),
+            #[cfg(feature = "kms")]
+            (KeyProvider::Kms(client, _), Some(("kms", _))) => {
+                let resp = client
+                    .decrypt()
+                    .ciphertext_blob(aws_sdk_kms::types::Blob::new(wrapped))
+                    .send()
+                    .await?;
+                let blob = resp.plaintext().context("missing KMS plaintext")?;
+                Ok(blob.as_ref().to_vec())
+            }
+            _ => bail!("template was encrypted with unavailable key {kek}"),
+        }
+    }
+}
+
+/// A store that encrypts templates before passing them to another store.
+///
+/// Each template is encrypted with a fresh data key, which is itself encrypted
+/// by a [`KeyProvider`] and stored alongside it. Templates that were stored in
+/// plaintext, before encryption was enabled, are still read as they are.
+pub struct EncryptedStore {
+    inner: Box<dyn TemplateStore>,
+    keys: KeyProvider,
+}
+
+impl EncryptedStore {
+    /// Encrypt templates stored in another store.
+    pub fn new(inner: impl TemplateStore + 'static, keys: KeyProvider) -> Self {
+        Self {
+            inner: Box::new(inner),
+            keys,
+        }
+    }
+
+    /// Re-encrypt every template that is stored in plaintext or under a key
+    /// other than the active one, returning their names.
+    ///
+    /// This completes a key rotation or a migration from plaintext storage.
+    pub async fn reencrypt(&self) -> Result<Vec<EnvName>> {
+        let active = self.keys.active_id();
+        let mut changed = Vec::new();
+        for env in self.inner.list().await? {
+            let stored = self.inner.get(&env).await?;
+            let current = match envelope(&stored) {
+                Some(envelope) => envelope?.kek == active,
+                None => false,
+            };
+            if !current {
+                let value = self.decrypt(&env, stored).await?;
+                self.inner
+                    .set(&env, &self.encrypt(&env, &value).await?)
+                    .await?;
+                changed.push(env);
+            }
+        }
+        if !changed.is_empty() {
+            changed.sort_unstable();
+            info!(?changed, "re-encrypted templates");
+        }
+        Ok(changed)
+    }
+
+    async fn encrypt(&self, env: &EnvName, value: &Value) -> Result<Value> {
+        let dek = Aes256Gcm::generate_key(&mut OsRng);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        // Binding the environment name means that a stored template cannot be
+        // swapped with another one without detection.
+        let payload = Payload {
+            msg: &serde_json::to_vec(value)?,
+            aad: env.as_str().as_bytes(),
+        };
+        let data = Aes256Gcm::new(&dek)
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("could not encrypt template"))?;
+        let (kek, wrapped) = self.keys.wrap(&dek).await?;
+        let envelope = Envelope {
+            kek,
+            dek: base64::encode(wrapped),
+            nonce: base64::encode(nonce),
+            data: base64::encode(data),
+        };
+        let mut value = serde_json::Map::new();
+        value.insert(ENVELOPE_FIELD.into(), serde_json::to_value(envelope)?);
+        Ok(Value::Object(value))
+    }
+
+    async fn decrypt(&self, env: &EnvName, stored: Value) -> Result<Value> {
+        let envelope = match envelope(&stored) {
+            Some(envelope) => envelope?,
+            None => return Ok(stored),
+        };
+        let dek = self
+            .keys
+            .unwrap(&envelope.kek, &base64::decode(&envelope.dek)?)
+            .await?;
+        ensure!(dek.len() == 32, "data key must be 32 bytes long");
+        let nonce = base64::decode(&envelope.nonce)?;
+        ensure!(nonce.len() == NONCE_LEN, "invalid nonce length");
+        let payload = Payload {
+            msg: &base64::decode(&envelope.data)?,
+            aad: env.as_str().as_bytes(),
+        };
+        let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&dek))
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow!("could not decrypt template"))?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+/// Parse the envelope of an encrypted template, or return `None` if the value
+/// is a plaintext template.
+fn envelope(value: &Value) -> Option<Result<Envelope>> {
+    match value.as_object() {
+        Some(object) if object.len() == 1 => {
+            let envelope = object.get(ENVELOPE_FIELD)?;
+            Some(serde_json::from_value(envelope.clone()).context("invalid encrypted template"))
+        }
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl TemplateStore for EncryptedStore {
+    async fn get(&self, env: &EnvName) -> Result<Value> {
+        let stored = self.inner.get(env).await?;
+        self.decrypt(env, stored).await
+    }
+
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        let stored = self.encrypt(env, value).await?;
+        self.inner.set(env, &stored).await
+    }
+
+    async fn delete(&self, env: &EnvName) -> Result<()> {
+        self.inner.delete(env).await
+    }
+
+    async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        self.inner.versions().await
+    }
+
+    async fn list(&self) -> Result<Vec<EnvName>> {
+        self.inner.list().await
+    }
+
+    fn watch(&self) -> Option<broadcast::Receiver<EnvName>> {
+        self.inner.watch()
+    }
+
+    async fn compare_and_set(&self, env: &EnvName, value: &Value, expected: &str) -> Result<()> {
+        let stored = self.encrypt(env, value).await?;
+        self.inner.compare_and_set(env, &stored, expected).await
+    }
+
+    async fn history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+        self.inner.history(env).await
+    }
+
+    async fn get_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+        let stored = self.inner.get_revision(env, id).await?;
+        self.decrypt(env, stored).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use super::{EncryptedStore, KeyProvider, Keyring};
+    use crate::server::storage::{MemoryStore, TemplateStore};
+
+    /// Share a memory store, so tests can inspect what was stored.
+    fn encrypted(inner: &Arc<MemoryStore>, keys: &str) -> Result<EncryptedStore> {
+        let keys = KeyProvider::Local(keys.parse::<Keyring>()?);
+        Ok(EncryptedStore::new(Arc::clone(inner), keys))
+    }
+
+    #[tokio::test]
+    async fn encryption_and_rotation() -> Result<()> {
+        let inner = Arc::new(MemoryStore::new());
+        let (old_key, new_key) = (Keyring::generate("old"), Keyring::generate("new"));
+        let (a, b) = ("a".parse()?, "b".parse()?);
+
+        // Legacy plaintext templates are read as they are.
+        inner.set(&a, &json!({ "legacy": true })).await?;
+        let store = encrypted(&inner, &old_key)?;
+        assert_eq!(store.get(&a).await?, json!({ "legacy": true }));
+
+        store.set(&b, &json!({ "password": "hunter2" })).await?;
+        assert_eq!(store.get(&b).await?, json!({ "password": "hunter2" }));
+        let stored = inner.get(&b).await?.to_string();
+        assert!(stored.contains("local:old"));
+        assert!(!stored.contains("hunter2"));
+
+        // Templates cannot be moved to another environment.
+        inner.set(&a, &inner.get(&b).await?).await?;
+        assert!(store.get(&a).await.is_err());
+        inner.set(&a, &json!({ "legacy": true })).await?;
+
+        // After rotating keys, old templates are readable until re-encrypted.
+        let store = encrypted(&inner, &format!("{old_key}\n{new_key}\n"))?;
+        assert_eq!(store.get(&b).await?, json!({ "password": "hunter2" }));
+        assert_eq!(store.reencrypt().await?, vec![a.clone(), b.clone()]);
+        assert!(store.reencrypt().await?.is_empty());
+        assert!(inner.get(&a).await?.to_string().contains("local:new"));
+
+        let store = encrypted(&inner, &new_key)?;
+        assert_eq!(store.get(&a).await?, json!({ "legacy": true }));
+        assert_eq!(store.get(&b).await?, json!({ "password": "hunter2" }));
+        assert!(encrypted(&inner, &old_key)?.get(&b).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_key_files() {
+        assert!("".parse::<Keyring>().is_err());
+        assert!("nocolon".parse::<Keyring>().is_err());
+        assert!("short:AAAA".parse::<Keyring>().is_err());
+    }
+}
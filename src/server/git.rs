@@ -0,0 +1,396 @@
+//! Storage of templates as commits in a local git repository.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use git2::build::{CheckoutBuilder, TreeUpdateBuilder};
+use git2::{
+    Commit, Cred, CredentialType, ErrorCode, FileMode, ObjectType, Oid, PushOptions,
+    RemoteCallbacks, Repository, Signature, Tree, TreeWalkMode, TreeWalkResult,
+};
+use parking_lot::Mutex;
+use serde_json::Value;
+use tokio::task;
+use tracing::{info, warn};
+
+use super::name::EnvName;
+use super::storage::{Revision, TemplateNotFound, TemplateStore};
+
+/// A git repository holding one `{env}.json` file per template.
+///
+/// Templates are read from and committed to the tip of a single branch, so the
+/// repository may be either bare or have a working tree. If the branch is
+/// checked out, the working tree is updated to match every commit.
+#[derive(Clone, Debug)]
+pub struct GitStore {
+    path: PathBuf,
+    branch: Option<String>,
+    remote: Option<String>,
+    author_name: String,
+    author_email: String,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl GitStore {
+    /// Use the git repository at a path, committing to its `HEAD` branch.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            branch: None,
+            remote: None,
+            author_name: "cadre".into(),
+            author_email: "cadre@localhost".into(),
+            write_lock: Default::default(),
+        }
+    }
+
+    /// Commit to a specific branch, instead of the one `HEAD` points to.
+    pub fn branch(mut self, branch: &str) -> Self {
+        self.branch = Some(branch.into());
+        self
+    }
+
+    /// Push the branch to a named remote after every commit.
+    pub fn remote(mut self, remote: &str) -> Self {
+        self.remote = Some(remote.into());
+        self
+    }
+
+    /// Set the author name and email recorded on commits.
+    pub fn author(mut self, name: &str, email: &str) -> Self {
+        self.author_name = name.into();
+        self.author_email = email.into();
+        self
+    }
+
+    /// Return the path of this repository.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Retrieve a template from the tip of the branch.
+    pub(crate) fn get(&self, env: &EnvName) -> Result<Value> {
+        let repo = Repository::open(&self.path)?;
+        let tip = self.tip(&repo)?;
+        let tree = tip.ok_or_else(|| TemplateNotFound(env.clone()))?.tree()?;
+        read_template(&repo, &tree, env)
+    }
+
+    /// Retrieve a template as of a given commit.
+    pub(crate) fn get_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+        let repo = Repository::open(&self.path)?;
+        let tree = repo.find_commit(Oid::from_str(id)?)?.tree()?;
+        read_template(&repo, &tree, env)
+    }
+
+    /// Commit a new version of a template to the branch.
+    pub(crate) fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        let content = serde_json::to_vec_pretty(value)?;
+        self.commit(env, Some(&content), &format!("Update {env}"))
+    }
+
+    /// Commit the removal of a template from the branch, if it exists.
+    pub(crate) fn delete(&self, env: &EnvName) -> Result<()> {
+        self.commit(env, None, &format!("Delete {env}"))
+    }
+
+    /// Commit a change to a single template, writing `content` or removing it
+    /// if `None`. No commit is made if this would not change anything.
+    fn commit(&self, env: &EnvName, content: Option<&[u8]>, message: &str) -> Result<()> {
+        let _guard = self.write_lock.lock();
+        let repo = Repository::open(&self.path)?;
+        let reference = self.reference(&repo)?;
+        let parent = self.tip(&repo)?;
+        let base = match &parent {
+            Some(commit) => commit.tree()?,
+            None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+        };
+
+        let path = template_path(env);
+        let mut update = TreeUpdateBuilder::new();
+        match content {
+            Some(content) => update.upsert(&path, repo.blob(content)?, FileMode::Blob),
+            None if base.get_path(Path::new(&path)).is_ok() => update.remove(&path),
+            None => return Ok(()),
+        };
+        let tree = repo.find_tree(update.create_updated(&repo, &base)?)?;
+
+        let signature = Signature::now(&self.author_name, &self.author_email)?;
+        let parents: Vec<&Commit> = parent.iter().collect();
+        repo.commit(
+            Some(&reference),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?;
+
+        if !repo.is_bare() && repo.find_reference("HEAD")?.symbolic_target() == Some(&reference) {
+            repo.checkout_head(Some(
+                CheckoutBuilder::new()
+                    .force()
+                    .remove_untracked(true)
+                    .path(&path),
+            ))?;
+        }
+
+        if let Some(remote) = &self.remote {
+            // The commit is already durable locally, and will be pushed again
+            // along with the next one, so a failed push is not fatal.
+            if let Err(err) = push(&repo, remote, &reference) {
+                warn!(%remote, ?err, "problem pushing templates to remote");
+            }
+        }
+        Ok(())
+    }
+
+    /// List all templates at the tip of the branch, along with the object ID
+    /// of each one as its version tag.
+    pub(crate) fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        let repo = Repository::open(&self.path)?;
+        let tree = match self.tip(&repo)? {
+            Some(commit) => commit.tree()?,
+            None => return Ok(HashMap::new()),
+        };
+        let mut results = HashMap::new();
+        tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                let env = entry
+                    .name()
+                    .and_then(|name| name.strip_suffix(".json"))
+                    .and_then(|name| EnvName::new(format!("{root}{name}")).ok());
+                if let Some(env) = env {
+                    results.insert(env, entry.id().to_string());
+                }
+            }
+            TreeWalkResult::Ok
+        })?;
+        Ok(results)
+    }
+
+    /// List the commits that changed a template, most recent first.
+    pub(crate) fn history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+        let repo = Repository::open(&self.path)?;
+        let tip = match self.tip(&repo)? {
+            Some(commit) => commit,
+            None => return Ok(Vec::new()),
+        };
+        let path = template_path(env);
+        let blob_id = |commit: &Commit| -> Result<Option<Oid>> {
+            Ok(commit
+                .tree()?
+                .get_path(Path::new(&path))
+                .ok()
+                .map(|e| e.id()))
+        };
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(tip.id())?;
+        let mut revisions = Vec::new();
+        for id in revwalk {
+            let commit = repo.find_commit(id?)?;
+            let current = match blob_id(&commit)? {
+                Some(current) => current,
+                None => continue,
+            };
+            let previous = match commit.parents().next() {
+                Some(parent) => blob_id(&parent)?,
+                None => None,
+            };
+            if previous != Some(current) {
+                revisions.push(Revision {
+                    id: commit.id().to_string(),
+                    author: commit.author().name().map(String::from),
+                    message: commit.message().map(|m| m.trim_end().into()),
+                    timestamp: commit.time().seconds(),
+                });
+            }
+        }
+        Ok(revisions)
+    }
+
+    /// Return the full name of the branch reference that templates live on.
+    fn reference(&self, repo: &Repository) -> Result<String> {
+        match &self.branch {
+            Some(branch) => Ok(format!("refs/heads/{branch}")),
+            None => match repo.find_reference("HEAD")?.symbolic_target() {
+                Some(target) => Ok(target.into()),
+                None => bail!("HEAD is detached, so a branch must be specified"),
+            },
+        }
+    }
+
+    /// Return the commit at the tip of the branch, if it has been created.
+    fn tip<'r>(&self, repo: &'r Repository) -> Result<Option<Commit<'r>>> {
+        match repo.find_reference(&self.reference(repo)?) {
+            Ok(reference) => Ok(Some(reference.peel_to_commit()?)),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl TemplateStore for GitStore {
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, env: &EnvName) -> Result<Value> {
+        info!("reading template");
+        let (git, env) = (self.clone(), env.clone());
+        task::spawn_blocking(move || git.get(&env)).await?
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        info!("writing template");
+        let (git, env, value) = (self.clone(), env.clone(), value.clone());
+        task::spawn_blocking(move || git.set(&env, &value)).await?
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete(&self, env: &EnvName) -> Result<()> {
+        info!("deleting template");
+        let (git, env) = (self.clone(), env.clone());
+        task::spawn_blocking(move || git.delete(&env)).await?
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        info!("listing template versions");
+        let git = self.clone();
+        task::spawn_blocking(move || git.versions()).await?
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+        info!("listing template history");
+        let (git, env) = (self.clone(), env.clone());
+        task::spawn_blocking(move || git.history(&env)).await?
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+        info!("reading template revision");
+        let (git, env, id) = (self.clone(), env.clone(), id.to_owned());
+        task::spawn_blocking(move || git.get_revision(&env, &id)).await?
+    }
+}
+
+/// Return the path of a template within the repository.
+fn template_path(env: &EnvName) -> String {
+    format!("{env}.json")
+}
+
+/// Read a template from a tree.
+fn read_template(repo: &Repository, tree: &Tree, env: &EnvName) -> Result<Value> {
+    let entry = tree
+        .get_path(Path::new(&template_path(env)))
+        .map_err(|err| match err.code() {
+            ErrorCode::NotFound => TemplateNotFound(env.clone()).into(),
+            _ => anyhow::Error::from(err),
+        })?;
+    let blob = repo.find_blob(entry.id())?;
+    Ok(serde_json::from_slice(blob.content())?)
+}
+
+/// Push a branch to a remote, authenticating with the usual git mechanisms.
+fn push(repo: &Repository, remote: &str, reference: &str) -> Result<()> {
+    let config = repo.config()?;
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|url, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            Cred::credential_helper(&config, url, username)
+        } else {
+            Cred::default()
+        }
+    });
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    let refspec = format!("{reference}:{reference}");
+    repo.find_remote(remote)?
+        .push(&[refspec.as_str()], Some(&mut options))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use git2::Repository;
+    use serde_json::json;
+
+    use super::GitStore;
+
+    #[test]
+    fn bare_repository() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        Repository::init_bare(dir.path())?;
+        let store = GitStore::new(dir.path()).author("Test", "test@example.com");
+        let (hello, nested) = ("hello".parse()?, "team/svc/prod".parse()?);
+
+        assert!(store.get(&hello).is_err());
+        assert!(store.versions()?.is_empty());
+        assert!(store.history(&hello)?.is_empty());
+
+        store.set(&hello, &json!({ "a": 1 }))?;
+        store.set(&nested, &json!({ "b": 2 }))?;
+        store.set(&hello, &json!({ "a": 3 }))?;
+        assert_eq!(store.get(&hello)?, json!({ "a": 3 }));
+        assert_eq!(store.get(&nested)?, json!({ "b": 2 }));
+
+        let versions = store.versions()?;
+        assert_eq!(versions.len(), 2);
+        assert!(versions.contains_key(&nested));
+
+        let history = store.history(&hello)?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].author.as_deref(), Some("Test"));
+        assert_eq!(history[0].message.as_deref(), Some("Update hello"));
+        assert_eq!(
+            store.get_revision(&hello, &history[1].id)?,
+            json!({ "a": 1 })
+        );
+        assert_eq!(store.history(&nested)?.len(), 1);
+
+        store.delete(&nested)?;
+        store.delete(&nested)?;
+        assert!(store.get(&nested).is_err());
+        assert_eq!(store.history(&hello)?.len(), 2);
+        assert_eq!(store.versions()?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn working_tree_and_remote() -> Result<()> {
+        let remote_dir = tempfile::tempdir()?;
+        let remote = Repository::init_bare(remote_dir.path())?;
+        let dir = tempfile::tempdir()?;
+        let repo = Repository::init(dir.path())?;
+        repo.remote("origin", remote_dir.path().to_str().unwrap())?;
+
+        let store = GitStore::new(dir.path()).branch("main").remote("origin");
+        repo.set_head("refs/heads/main")?;
+        let hello = "hello".parse()?;
+        store.set(&hello, &json!("world"))?;
+
+        // The checked out working tree and the remote both see the commit.
+        let content = std::fs::read_to_string(dir.path().join("hello.json"))?;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&content)?,
+            json!("world")
+        );
+        assert!(repo.statuses(None)?.is_empty());
+        assert!(remote.find_reference("refs/heads/main").is_ok());
+
+        store.delete(&hello)?;
+        assert!(!dir.path().join("hello.json").exists());
+        assert!(repo.statuses(None)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+//! Storage that replicates templates from a primary store to a secondary one.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{info, warn};
+
+use super::name::EnvName;
+use super::storage::{Revision, TemplateNotFound, TemplateStore};
+
+/// A primary store whose templates are copied to a secondary store, which
+/// serves reads whenever the primary is unavailable.
+///
+/// Writes go to the primary first, and only succeed if it accepts them. They
+/// are then copied to the secondary on a best-effort basis, so a replica keeps
+/// a warm copy of every template, such as a local directory in front of S3.
+/// Copies missed while the secondary was failing, or writes made through other
+/// replicas, are repaired by [`LayeredStore::reconcile`].
+pub struct LayeredStore {
+    primary: Box<dyn TemplateStore>,
+    secondary: Box<dyn TemplateStore>,
+
+    /// Version tag in the primary of each template that was last reconciled,
+    /// so that unchanged templates are not read again.
+    reconciled: Mutex<HashMap<EnvName, String>>,
+}
+
+impl LayeredStore {
+    /// Layer a primary store on top of a secondary one.
+    pub fn new(
+        primary: impl TemplateStore + 'static,
+        secondary: impl TemplateStore + 'static,
+    ) -> Self {
+        Self {
+            primary: Box::new(primary),
+            secondary: Box::new(secondary),
+            reconciled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bring the secondary store up to date with the primary, returning the
+    /// names of all templates that were copied or removed.
+    ///
+    /// Only templates whose version changed since the last run are read.
+    /// Templates that cannot be copied are skipped, and retried on the next
+    /// run. This should run on startup, before the secondary is relied upon,
+    /// and then periodically with [`LayeredStore::spawn_reconcile`].
+    pub async fn reconcile(&self) -> Result<Vec<EnvName>> {
+        let versions = self.primary.versions().await?;
+        let secondary: HashSet<EnvName> = self.secondary.list().await?.into_iter().collect();
+        self.reconciled
+            .lock()
+            .retain(|env, _| versions.contains_key(env));
+
+        let mut changed = Vec::new();
+        for (env, tag) in &versions {
+            let reconciled = self.reconciled.lock().get(env) == Some(tag);
+            if reconciled && secondary.contains(env) {
+                continue;
+            }
+            match self.reconcile_one(env).await {
+                Ok(copied) => {
+                    self.reconciled.lock().insert(env.clone(), tag.clone());
+                    if copied {
+                        changed.push(env.clone());
+                    }
+                }
+                Err(err) => warn!(%env, ?err, "problem reconciling template"),
+            }
+        }
+        for env in secondary {
+            if versions.contains_key(&env) {
+                continue;
+            }
+            match self.secondary.delete(&env).await {
+                Ok(()) => changed.push(env),
+                Err(err) => warn!(%env, ?err, "problem reconciling template"),
+            }
+        }
+        if !changed.is_empty() {
+            changed.sort_unstable();
+            info!(?changed, "reconciled secondary storage");
+        }
+        Ok(changed)
+    }
+
+    /// Copy a single template to the secondary store if it differs there, or
+    /// remove it if it was deleted from the primary since it was listed.
+    /// Returns true if the secondary was changed.
+    async fn reconcile_one(&self, env: &EnvName) -> Result<bool> {
+        let value = match self.primary.get(env).await {
+            Ok(value) => Some(value),
+            Err(err) if err.is::<TemplateNotFound>() => None,
+            Err(err) => return Err(err),
+        };
+        let current = match self.secondary.get(env).await {
+            Ok(current) => Some(current),
+            Err(err) if err.is::<TemplateNotFound>() => None,
+            Err(err) => return Err(err),
+        };
+        if current == value {
+            return Ok(false);
+        }
+        match &value {
+            Some(value) => self.secondary.set(env, value).await?,
+            None => self.secondary.delete(env).await?,
+        }
+        Ok(true)
+    }
+
+    /// Reconcile the secondary store in the background at a fixed interval,
+    /// for as long as the layered store is alive.
+    pub fn spawn_reconcile(self: &Arc<Self>, interval: Duration) {
+        let store = Arc::downgrade(self);
+        tokio::spawn(reconcile_periodically(store, interval));
+    }
+
+    /// Copy a successful write to the secondary store.
+    async fn replicate(&self, env: &EnvName, value: Option<&Value>) {
+        let result = match value {
+            Some(value) => self.secondary.set(env, value).await,
+            None => self.secondary.delete(env).await,
+        };
+        if let Err(err) = result {
+            warn!(%env, ?err, "problem replicating template to secondary storage");
+        }
+    }
+}
+
+#[async_trait]
+impl TemplateStore for LayeredStore {
+    async fn get(&self, env: &EnvName) -> Result<Value> {
+        match self.primary.get(env).await {
+            Ok(value) => Ok(value),
+            // The template was deleted, so any copy in the secondary is stale.
+            Err(err) if err.is::<TemplateNotFound>() => {
+                self.replicate(env, None).await;
+                Err(err)
+            }
+            Err(err) => {
+                warn!(%env, ?err, "reading template from secondary storage");
+                self.secondary.get(env).await
+            }
+        }
+    }
+
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        self.primary.set(env, value).await?;
+        self.replicate(env, Some(value)).await;
+        Ok(())
+    }
+
+    async fn delete(&self, env: &EnvName) -> Result<()> {
+        self.primary.delete(env).await?;
+        self.replicate(env, None).await;
+        Ok(())
+    }
+
+    async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        match self.primary.versions().await {
+            Ok(versions) => Ok(versions),
+            Err(err) => {
+                warn!(?err, "listing templates from secondary storage");
+                self.secondary.versions().await
+            }
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<EnvName>> {
+        match self.primary.list().await {
+            Ok(templates) => Ok(templates),
+            Err(err) => {
+                warn!(?err, "listing templates from secondary storage");
+                self.secondary.list().await
+            }
+        }
+    }
+
+    fn watch(&self) -> Option<broadcast::Receiver<EnvName>> {
+        self.primary.watch()
+    }
+
+    async fn compare_and_set(&self, env: &EnvName, value: &Value, expected: &str) -> Result<()> {
+        self.primary.compare_and_set(env, value, expected).await?;
+        self.replicate(env, Some(value)).await;
+        Ok(())
+    }
+
+    async fn history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+        self.primary.history(env).await
+    }
+
+    async fn get_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+        self.primary.get_revision(env, id).await
+    }
+}
+
+/// Background task that reconciles a layered store at a fixed interval.
+async fn reconcile_periodically(store: Weak<LayeredStore>, interval: Duration) {
+    let mut interval = time::interval(interval);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => break,
+        };
+        if let Err(err) = store.reconcile().await {
+            warn!(?err, "problem reconciling secondary storage");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::{bail, Result};
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+    use serde_json::{json, Value};
+    use tokio::time::{self, Instant};
+
+    use super::LayeredStore;
+    use crate::server::name::EnvName;
+    use crate::server::storage::{MemoryStore, TemplateNotFound, TemplateStore};
+
+    /// Store that fails every operation while it is marked as down, and
+    /// writes to any template marked as broken.
+    #[derive(Clone, Default)]
+    struct Flaky {
+        inner: Arc<MemoryStore>,
+        down: Arc<AtomicBool>,
+        broken: Arc<Mutex<Option<EnvName>>>,
+        gets: Arc<AtomicUsize>,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                bail!("store is down");
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl TemplateStore for Flaky {
+        async fn get(&self, env: &EnvName) -> Result<Value> {
+            self.check()?;
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(env).await
+        }
+
+        async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+            self.check()?;
+            if self.broken.lock().as_ref() == Some(env) {
+                bail!("cannot write {env}");
+            }
+            self.inner.set(env, value).await
+        }
+
+        async fn delete(&self, env: &EnvName) -> Result<()> {
+            self.check()?;
+            self.inner.delete(env).await
+        }
+
+        async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+            self.check()?;
+            self.inner.versions().await
+        }
+    }
+
+    #[tokio::test]
+    async fn failover_reads() -> Result<()> {
+        let (primary, secondary) = (Flaky::default(), Flaky::default());
+        let store = LayeredStore::new(primary.clone(), secondary.clone());
+        let (a, b) = ("a".parse()?, "b".parse()?);
+
+        store.set(&a, &json!(1)).await?;
+        assert_eq!(secondary.get(&a).await?, json!(1));
+
+        // Reads fall back to the secondary while the primary is down, but
+        // writes must reach the primary.
+        primary.down.store(true, Ordering::SeqCst);
+        assert_eq!(store.get(&a).await?, json!(1));
+        assert_eq!(store.list().await?, vec![a.clone()]);
+        assert!(store.set(&b, &json!(2)).await.is_err());
+        assert!(secondary.get(&b).await.is_err());
+        primary.down.store(false, Ordering::SeqCst);
+
+        // Writes succeed even if the secondary misses them.
+        secondary.down.store(true, Ordering::SeqCst);
+        store.set(&a, &json!(3)).await?;
+        store.set(&b, &json!(4)).await?;
+        secondary.down.store(false, Ordering::SeqCst);
+        assert_eq!(secondary.get(&a).await?, json!(1));
+
+        secondary.set(&"stale".parse()?, &json!(5)).await?;
+        assert_eq!(
+            store.reconcile().await?,
+            vec![a.clone(), b.clone(), "stale".parse()?]
+        );
+        assert_eq!(secondary.get(&a).await?, json!(3));
+        assert_eq!(secondary.get(&b).await?, json!(4));
+        assert_eq!(secondary.list().await?.len(), 2);
+        assert!(store.reconcile().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconcile_changes() -> Result<()> {
+        let (primary, secondary) = (Flaky::default(), Flaky::default());
+        let store = LayeredStore::new(primary.clone(), secondary.clone());
+        let (a, b): (EnvName, EnvName) = ("a".parse()?, "b".parse()?);
+
+        primary.set(&a, &json!(1)).await?;
+        primary.set(&b, &json!(2)).await?;
+        assert_eq!(store.reconcile().await?, vec![a.clone(), b.clone()]);
+
+        // Templates that did not change since the last run are not read.
+        let gets = primary.gets.load(Ordering::SeqCst);
+        assert!(store.reconcile().await?.is_empty());
+        assert_eq!(primary.gets.load(Ordering::SeqCst), gets);
+        primary.set(&a, &json!(3)).await?;
+        assert_eq!(store.reconcile().await?, vec![a.clone()]);
+        assert_eq!(primary.gets.load(Ordering::SeqCst), gets + 1);
+
+        // A template that cannot be copied does not hold up the others.
+        *secondary.broken.lock() = Some(b.clone());
+        primary.set(&a, &json!(4)).await?;
+        primary.set(&b, &json!(5)).await?;
+        assert_eq!(store.reconcile().await?, vec![a.clone()]);
+        assert_eq!(secondary.get(&b).await?, json!(2));
+        *secondary.broken.lock() = None;
+        assert_eq!(store.reconcile().await?, vec![b.clone()]);
+        assert_eq!(secondary.get(&b).await?, json!(5));
+
+        // Templates removed from the secondary are copied again.
+        secondary.delete(&a).await?;
+        assert_eq!(store.reconcile().await?, vec![a.clone()]);
+        assert_eq!(secondary.get(&a).await?, json!(4));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_elsewhere() -> Result<()> {
+        let (primary, secondary) = (Flaky::default(), Flaky::default());
+        let store = LayeredStore::new(primary.clone(), secondary.clone());
+        let a = "a".parse()?;
+
+        // Another replica deletes the template directly from the primary.
+        store.set(&a, &json!(1)).await?;
+        primary.delete(&a).await?;
+        let err = store.get(&a).await.unwrap_err();
+        assert!(err.is::<TemplateNotFound>());
+        assert!(secondary.get(&a).await.is_err());
+
+        // Periodic reconciliation catches writes that were never read.
+        store.set(&a, &json!(2)).await?;
+        primary.set(&a, &json!(3)).await?;
+        let store = Arc::new(store);
+        store.spawn_reconcile(Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while secondary.get(&a).await? != json!(3) {
+            assert!(Instant::now() < deadline, "secondary was not reconciled");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! In-memory mirror of all templates, kept in sync with storage.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use parking_lot::RwLock;
+use serde_json::Value;
+use tracing::info;
+
+use super::name::EnvName;
+use super::storage::{TemplateNotFound, TemplateStore};
+
+/// A copy of every template in storage, held in memory.
+///
+/// Each template is stored alongside the version tag it had when it was read,
+/// so that [`Mirror::sync`] only needs to fetch templates that have changed.
+#[derive(Debug, Default)]
+pub struct Mirror {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    templates: HashMap<EnvName, (Option<String>, Value)>,
+
+    /// Counter of local writes, which is incremented by each one.
+    generation: u64,
+
+    /// Generation of the last local write to each template, for writes that
+    /// happened during a sync.
+    written: HashMap<EnvName, u64>,
+}
+
+impl Inner {
+    fn record_write(&mut self, env: &EnvName) {
+        self.generation += 1;
+        self.written.insert(env.clone(), self.generation);
+    }
+}
+
+impl Mirror {
+    /// Create a new mirror holding all templates currently in storage.
+    pub async fn load(storage: &dyn TemplateStore) -> Result<Self> {
+        let mirror = Self::default();
+        mirror.sync(storage).await?;
+        Ok(mirror)
+    }
+
+    /// Get a template from the mirror.
+    pub fn get(&self, env: &EnvName) -> Option<Value> {
+        let inner = self.inner.read();
+        inner.templates.get(env).map(|(_, value)| value.clone())
+    }
+
+    /// Record a template that was read from or written to storage.
+    ///
+    /// Its version tag is unknown, so it will be read again on the next sync.
+    pub fn insert(&self, env: &EnvName, value: Value) {
+        let mut inner = self.inner.write();
+        inner.record_write(env);
+        inner.templates.insert(env.clone(), (None, value));
+    }
+
+    /// Forget a template that was removed from storage.
+    pub fn remove(&self, env: &EnvName) {
+        let mut inner = self.inner.write();
+        inner.record_write(env);
+        inner.templates.remove(env);
+    }
+
+    /// List the names of all templates in the mirror.
+    pub fn list(&self) -> Vec<EnvName> {
+        self.inner.read().templates.keys().cloned().collect()
+    }
+
+    /// Bring the mirror up to date with storage, returning the names of all
+    /// templates that were added, changed or removed.
+    ///
+    /// Templates written locally while the sync is running are left as they
+    /// are, since storage may have been listed before the write.
+    pub async fn sync(&self, storage: &dyn TemplateStore) -> Result<Vec<EnvName>> {
+        let start = self.inner.read().generation;
+        let versions = storage.versions().await?;
+
+        let mut changed: Vec<EnvName> = {
+            let templates = &self.inner.read().templates;
+            let mut changed: Vec<EnvName> = templates
+                .keys()
+                .filter(|env| !versions.contains_key(*env))
+                .cloned()
+                .collect();
+            changed.extend(
+                versions
+                    .iter()
+                    .filter_map(|(env, tag)| match templates.get(env) {
+                        Some((Some(old_tag), _)) if old_tag == tag => None,
+                        _ => Some(env.clone()),
+                    }),
+            );
+            changed
+        };
+
+        // Templates removed since they were listed have no update.
+        let mut updates = Vec::new();
+        for env in &changed {
+            if let Some(tag) = versions.get(env) {
+                match storage.get(env).await {
+                    Ok(value) => updates.push((env.clone(), Some((tag.clone(), value)))),
+                    Err(err) if err.is::<TemplateNotFound>() => updates.push((env.clone(), None)),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let mut inner = self.inner.write();
+        let Inner {
+            templates, written, ..
+        } = &mut *inner;
+        let written_since =
+            |env: &EnvName| matches!(written.get(env), Some(&generation) if generation > start);
+        templates.retain(|env, _| versions.contains_key(env) || written_since(env));
+        for (env, update) in updates {
+            if written_since(&env) {
+                continue;
+            }
+            match update {
+                Some((tag, value)) => templates.insert(env, (Some(tag), value)),
+                None => templates.remove(&env),
+            };
+        }
+        written.retain(|_, generation| *generation > start);
+        drop(inner);
+
+        if !changed.is_empty() {
+            changed.sort_unstable();
+            info!(?changed, "synced template mirror");
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+
+    use super::Mirror;
+    use crate::server::name::EnvName;
+    use crate::server::storage::{MemoryStore, TemplateStore};
+
+    #[tokio::test]
+    async fn sync_changes() -> Result<()> {
+        let (a, b, c) = ("a".parse()?, "b".parse()?, "c".parse()?);
+        let storage = MemoryStore::new();
+        storage.set(&a, &json!(1)).await?;
+        storage.set(&b, &json!(2)).await?;
+
+        let mirror = Mirror::load(&storage).await?;
+        assert_eq!(mirror.get(&a), Some(json!(1)));
+        assert!(mirror.sync(&storage).await?.is_empty());
+
+        storage.set(&b, &json!(3)).await?;
+        storage.set(&c, &json!(4)).await?;
+        assert_eq!(mirror.sync(&storage).await?, vec![b.clone(), c.clone()]);
+        assert_eq!(mirror.get(&b), Some(json!(3)));
+
+        // Local writes are fetched again, since their version is unknown.
+        mirror.insert(&a, json!(5));
+        assert_eq!(mirror.get(&a), Some(json!(5)));
+        assert_eq!(mirror.sync(&storage).await?, vec![a.clone()]);
+        assert_eq!(mirror.get(&a), Some(json!(1)));
+
+        storage.delete(&c).await?;
+        assert_eq!(mirror.sync(&storage).await?, vec![c.clone()]);
+        assert_eq!(mirror.get(&c), None);
+
+        Ok(())
+    }
+
+    /// Store that simulates a local write landing just after it is listed.
+    struct Racing {
+        inner: MemoryStore,
+        mirror: Arc<Mirror>,
+    }
+
+    #[async_trait]
+    impl TemplateStore for Racing {
+        async fn get(&self, env: &EnvName) -> Result<Value> {
+            self.inner.get(env).await
+        }
+
+        async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+            self.inner.set(env, value).await
+        }
+
+        async fn delete(&self, env: &EnvName) -> Result<()> {
+            self.inner.delete(env).await
+        }
+
+        async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+            let versions = self.inner.versions().await?;
+            let env = "new".parse()?;
+            self.inner.set(&env, &json!(1)).await?;
+            self.mirror.insert(&env, json!(1));
+            let old = "old".parse()?;
+            self.inner.delete(&old).await?;
+            self.mirror.remove(&old);
+            Ok(versions)
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_during_writes() -> Result<()> {
+        let mirror = Arc::new(Mirror::default());
+        let storage = Racing {
+            inner: MemoryStore::new(),
+            mirror: Arc::clone(&mirror),
+        };
+        let (new, old) = ("new".parse()?, "old".parse()?);
+        storage.inner.set(&old, &json!(2)).await?;
+
+        // Neither write is undone by the sync that raced with it, and the
+        // removed template is not an error even though it was listed.
+        mirror.sync(&storage).await?;
+        assert_eq!(mirror.get(&new), Some(json!(1)));
+        assert_eq!(mirror.get(&old), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Validated names for configuration environments.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of an environment name, in bytes.
+pub const MAX_NAME_LEN: usize = 128;
+
+/// Separator between segments of a hierarchical environment name.
+pub const SEPARATOR: char = '/';
+
+/// The name of a configuration environment.
+///
+/// Names consist of one or more segments joined by [`SEPARATOR`]. Each segment
+/// is made of ASCII letters, digits, `-`, `_` and `.`, and may not start with a
+/// `.` character. This makes names safe to use as file paths and object keys.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EnvName(String);
+
+impl EnvName {
+    /// Validate and create a new environment name.
+    pub fn new(name: impl Into<String>) -> Result<Self> {
+        let name = name.into();
+        ensure!(!name.is_empty(), "environment name is empty");
+        ensure!(
+            name.len() <= MAX_NAME_LEN,
+            "environment name is longer than {MAX_NAME_LEN} bytes"
+        );
+        for segment in name.split(SEPARATOR) {
+            ensure!(
+                !segment.is_empty(),
+                "environment name {name:?} has an empty segment"
+            );
+            ensure!(
+                !segment.starts_with('.'),
+                "environment name {name:?} has a segment starting with '.'"
+            );
+            if let Some(c) = segment
+                .chars()
+                .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+            {
+                bail!("environment name {name:?} contains invalid character {c:?}");
+            }
+        }
+        Ok(Self(name))
+    }
+
+    /// Return the name as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Return true if this name is nested anywhere under a namespace.
+    pub fn is_within(&self, namespace: &EnvName) -> bool {
+        matches!(
+            self.0.strip_prefix(namespace.as_str()),
+            Some(rest) if rest.starts_with(SEPARATOR)
+        )
+    }
+
+    /// Return every namespace that this name is nested under, outermost first.
+    pub fn namespaces(&self) -> Vec<EnvName> {
+        self.0
+            .match_indices(SEPARATOR)
+            .map(|(end, _)| Self(self.0[..end].into()))
+            .collect()
+    }
+
+    /// Return the direct child of a namespace that this name is nested under,
+    /// and whether that child is this name itself.
+    ///
+    /// A namespace of `None` refers to the root, containing every name.
+    pub fn child_of(&self, namespace: Option<&EnvName>) -> Option<(EnvName, bool)> {
+        let start = match namespace {
+            Some(namespace) if self.is_within(namespace) => namespace.0.len() + 1,
+            Some(_) => return None,
+            None => 0,
+        };
+        match self.0[start..].find(SEPARATOR) {
+            Some(end) => Some((Self(self.0[..start + end].into()), false)),
+            None => Some((self.clone(), true)),
+        }
+    }
+}
+
+impl fmt::Debug for EnvName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for EnvName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for EnvName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for EnvName {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl From<EnvName> for String {
+    fn from(name: EnvName) -> Self {
+        name.0
+    }
+}
+
+impl AsRef<str> for EnvName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for EnvName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnvName;
+
+    #[test]
+    fn valid_names() {
+        for name in ["default", "prod", "my-app_v1.2", "team/service/prod", "A9"] {
+            assert_eq!(EnvName::new(name).unwrap().as_str(), name);
+        }
+    }
+
+    #[test]
+    fn invalid_names() {
+        let long = "a".repeat(129);
+        for name in [
+            "",
+            ".",
+            "..",
+            "../etc/passwd",
+            "a/../b",
+            ".hidden",
+            "/abs",
+            "trailing/",
+            "a//b",
+            "back\\slash",
+            "spa ce",
+            "nul\0",
+            "ünïcode",
+            &long,
+        ] {
+            assert!(EnvName::new(name).is_err(), "{name:?} should be invalid");
+        }
+    }
+
+    #[test]
+    fn namespaces() {
+        let name = |s: &str| EnvName::new(s).unwrap();
+        let env = name("team/service/prod");
+        assert!(env.is_within(&name("team")));
+        assert!(env.is_within(&name("team/service")));
+        assert!(!env.is_within(&name("team/service/prod")));
+        assert!(!env.is_within(&name("tea")));
+        assert!(!env.is_within(&name("team/serv")));
+
+        assert_eq!(env.child_of(None), Some((name("team"), false)));
+        assert_eq!(
+            env.child_of(Some(&name("team"))),
+            Some((name("team/service"), false))
+        );
+        assert_eq!(
+            env.child_of(Some(&name("team/service"))),
+            Some((env.clone(), true))
+        );
+        assert_eq!(env.child_of(Some(&name("other"))), None);
+        assert_eq!(name("prod").child_of(None), Some((name("prod"), true)));
+
+        assert_eq!(env.namespaces(), vec![name("team"), name("team/service")]);
+        assert!(name("prod").namespaces().is_empty());
+    }
+
+    #[test]
+    fn deserialize_validates() {
+        assert!(serde_json::from_str::<EnvName>("\"prod\"").is_ok());
+        assert!(serde_json::from_str::<EnvName>("\"../prod\"").is_err());
+    }
+}
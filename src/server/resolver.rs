@@ -7,8 +7,9 @@ use async_trait::async_trait;
 use aws_sdk_secretsmanager::Client;
 use aws_types::sdk_config::SdkConfig;
 use serde_json::Value;
+use tokio::time::Duration;
 
-use super::cache::TimedCache;
+use super::cache::{CacheStats, TimedCache};
 
 /// Collection of resolvers for populating values in templates.
 #[derive(Default)]
@@ -45,6 +46,24 @@ impl ResolverChain {
         })?;
         resolver.resolve(name).await
     }
+
+    /// Return true if a templated value is resolved by a secret resolver.
+    pub fn is_secret(&self, value: &str) -> bool {
+        let prefix = value.split_once(':').map_or(value, |(prefix, _)| prefix);
+        matches!(self.map.get(prefix), Some(resolver) if resolver.is_secret())
+    }
+
+    /// Longest time, in seconds, that values resolved by this chain may be
+    /// reused before they could be stale.
+    ///
+    /// This is the minimum over all resolvers in the chain, or `None` if no
+    /// resolver produces values that go stale.
+    pub fn max_age(&self) -> Option<f64> {
+        self.map
+            .values()
+            .filter_map(|resolver| resolver.max_age())
+            .reduce(f64::min)
+    }
 }
 
 /// Trait for resolving special keys in templates.
@@ -55,8 +74,32 @@ pub trait Resolver: Send + Sync {
 
     /// Fetches a secret by value.
     async fn resolve(&self, name: &str) -> Result<Value>;
+
+    /// Longest time, in seconds, that a resolved value may be reused, which
+    /// bounds how long populated configs using this resolver are cached.
+    ///
+    /// Returns `None` if resolved values never go stale. The default is
+    /// `Some(0.0)`, so configs using a resolver that does not override this
+    /// are never cached. The age is counted from when [`Resolver::resolve`]
+    /// returns, so a resolver that caches values itself should account for
+    /// that cache when choosing it.
+    fn max_age(&self) -> Option<f64> {
+        Some(0.0)
+    }
+
+    /// Whether resolved values are secret, and should be redacted from
+    /// configs served to clients that may not read secrets.
+    fn is_secret(&self) -> bool {
+        false
+    }
 }
 
+/// Minimum time, in seconds, that secrets are cached for.
+const SECRET_MIN_TTL: f64 = 45.0;
+
+/// Maximum time, in seconds, that secrets are cached for.
+const SECRET_MAX_TTL: f64 = 60.0;
+
 /// Client for retrieving secrets from AWS Secrets Manager.
 pub struct AwsSecrets {
     client: Client,
@@ -65,12 +108,19 @@ pub struct AwsSecrets {
 
 impl AwsSecrets {
     /// Creates a new instance of secrets manager.
+    ///
+    /// This spawns a task to sweep expired secrets from the cache, so it must
+    /// be called from within a Tokio runtime.
     pub fn new(aws_config: &SdkConfig) -> Self {
         let client = Client::new(aws_config);
-        Self {
-            client,
-            cache: TimedCache::new(45.0, 60.0),
-        }
+        let cache = TimedCache::new(SECRET_MIN_TTL, SECRET_MAX_TTL);
+        cache.spawn_sweeper(Duration::from_secs(60));
+        Self { client, cache }
+    }
+
+    /// Returns hit, miss and eviction counters for the secrets cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
     }
 }
 
@@ -80,6 +130,16 @@ impl Resolver for AwsSecrets {
         "aws"
     }
 
+    fn max_age(&self) -> Option<f64> {
+        // Cached secrets may already be up to a minute old, so configs can lag
+        // a rotation by up to two minutes, in exchange for fewer requests.
+        Some(SECRET_MIN_TTL)
+    }
+
+    fn is_secret(&self) -> bool {
+        true
+    }
+
     async fn resolve(&self, name: &str) -> Result<Value> {
         if let Some(value) = self.cache.get(name) {
             return Ok(value);
@@ -113,14 +173,43 @@ impl Resolver for EchoJson {
     async fn resolve(&self, name: &str) -> Result<Value> {
         Ok(serde_json::from_str(name)?)
     }
+
+    fn max_age(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// Like [`EchoJson`], but treating its values as secrets, used for testing.
+#[doc(hidden)]
+pub struct EchoSecret;
+
+#[doc(hidden)]
+#[async_trait]
+impl Resolver for EchoSecret {
+    fn prefix(&self) -> &'static str {
+        "secret"
+    }
+
+    async fn resolve(&self, name: &str) -> Result<Value> {
+        Ok(serde_json::from_str(name)?)
+    }
+
+    fn max_age(&self) -> Option<f64> {
+        None
+    }
+
+    fn is_secret(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
-    use serde_json::json;
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
 
-    use super::{EchoJson, ResolverChain};
+    use super::{EchoJson, Resolver, ResolverChain};
 
     #[tokio::test]
     async fn empty_resolver() {
@@ -138,4 +227,27 @@ mod tests {
         assert!(!chain.add(EchoJson));
         Ok(())
     }
+
+    struct Uncached;
+
+    #[async_trait]
+    impl Resolver for Uncached {
+        fn prefix(&self) -> &'static str {
+            "uncached"
+        }
+
+        async fn resolve(&self, _name: &str) -> Result<Value> {
+            Ok(Value::Null)
+        }
+    }
+
+    #[test]
+    fn chain_max_age() {
+        let mut chain = ResolverChain::new();
+        assert_eq!(chain.max_age(), None);
+        chain.add(EchoJson);
+        assert_eq!(chain.max_age(), None);
+        chain.add(Uncached);
+        assert_eq!(chain.max_age(), Some(0.0));
+    }
 }
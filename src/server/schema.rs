@@ -0,0 +1,254 @@
+//! Validation of templates and configs against JSON Schemas.
+//!
+//! Schemas are stored alongside templates, under the name of the environment
+//! or namespace they apply to with a `.schema` suffix. A schema applies to the
+//! environment of the same name and to every environment nested within it, so
+//! a config may need to satisfy several schemas at once.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use anyhow::Result;
+use jsonschema::error::ValidationErrorKind;
+use jsonschema::JSONSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::name::{EnvName, SEPARATOR};
+use super::template::TEMPLATE_MARK;
+
+/// Suffix appended to the name of an environment or namespace to store its
+/// schema. Environment names cannot end with this suffix.
+pub const SCHEMA_SUFFIX: &str = ".schema";
+
+/// A single way in which a value does not match a schema.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaError {
+    /// Environment or namespace whose schema was violated.
+    pub schema: EnvName,
+
+    /// JSON Pointer to the offending part of the value.
+    pub path: String,
+
+    /// Description of the problem.
+    pub message: String,
+}
+
+/// Error returned when a value does not match its schemas, listing every
+/// violation that was found.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaViolation {
+    /// All violations, grouped by schema.
+    pub errors: Vec<SchemaError>,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "value does not match schema")?;
+        for error in &self.errors {
+            write!(
+                f,
+                "; {} at {:?}: {}",
+                error.schema, error.path, error.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SchemaViolation {}
+
+impl SchemaViolation {
+    /// Drop error messages, which may quote the offending values, keeping only
+    /// the location of each error.
+    pub fn redacted(&self) -> Self {
+        let errors = self.errors.iter().map(|error| SchemaError {
+            message: "value does not match schema".into(),
+            ..error.clone()
+        });
+        Self {
+            errors: errors.collect(),
+        }
+    }
+}
+
+/// Return true if a name is reserved for stored schemas rather than templates,
+/// because any of its segments ends with [`SCHEMA_SUFFIX`].
+pub fn is_schema_name(name: &EnvName) -> bool {
+    name.as_str()
+        .split(SEPARATOR)
+        .any(|segment| segment.ends_with(SCHEMA_SUFFIX))
+}
+
+/// Return the name that the schema for an environment or namespace is stored
+/// under.
+pub fn schema_name(env: &EnvName) -> Result<EnvName> {
+    EnvName::new(format!("{env}{SCHEMA_SUFFIX}"))
+}
+
+/// Return every environment or namespace whose schema applies to an
+/// environment, from the outermost namespace to the environment itself.
+pub fn schema_scopes(env: &EnvName) -> Vec<EnvName> {
+    let mut scopes = env.namespaces();
+    scopes.push(env.clone());
+    scopes
+}
+
+/// Check that the schema for an environment or namespace is itself valid.
+pub fn check_schema(env: &EnvName, schema: &Value) -> Result<(), SchemaViolation> {
+    match JSONSchema::compile(schema) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(SchemaViolation {
+            errors: vec![invalid_schema(env, &err.to_string())],
+        }),
+    }
+}
+
+/// Validate a value against a list of named schemas.
+///
+/// If `template` is true, the value is an unpopulated template. Its templated
+/// keys are ignored, since their values are only known once populated, and
+/// they count towards the properties that a schema requires.
+pub fn validate(
+    schemas: &[(EnvName, Value)],
+    value: &Value,
+    template: bool,
+) -> Result<(), SchemaViolation> {
+    let target = match template {
+        true => Cow::Owned(strip_templated(value)),
+        false => Cow::Borrowed(value),
+    };
+
+    let mut errors = Vec::new();
+    for (name, schema) in schemas {
+        let compiled = match JSONSchema::compile(schema) {
+            Ok(compiled) => compiled,
+            Err(err) => {
+                errors.push(invalid_schema(name, &err.to_string()));
+                continue;
+            }
+        };
+        let result = compiled.validate(&target);
+        if let Err(violations) = result {
+            for violation in violations {
+                let path = violation.instance_path.to_string();
+                if template && is_templated(value, &path, &violation.kind) {
+                    continue;
+                }
+                errors.push(SchemaError {
+                    schema: name.clone(),
+                    path,
+                    message: violation.to_string(),
+                });
+            }
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(SchemaViolation { errors }),
+    }
+}
+
+/// Return true if a violation is a missing property that the template at
+/// `path` provides as a templated key.
+fn is_templated(template: &Value, path: &str, kind: &ValidationErrorKind) -> bool {
+    let property = match kind {
+        ValidationErrorKind::Required { property } => property.as_str().unwrap_or_default(),
+        _ => return false,
+    };
+    match template.pointer(path) {
+        Some(Value::Object(map)) => map.contains_key(&format!("{TEMPLATE_MARK}{property}")),
+        _ => false,
+    }
+}
+
+fn invalid_schema(name: &EnvName, message: &str) -> SchemaError {
+    SchemaError {
+        schema: name.clone(),
+        path: String::new(),
+        message: format!("invalid schema: {message}"),
+    }
+}
+
+/// Remove every templated key from a value, recursively.
+fn strip_templated(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .filter(|(key, _)| !key.starts_with(TEMPLATE_MARK))
+                .map(|(key, value)| (key.clone(), strip_templated(value)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(strip_templated).collect()),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{check_schema, is_schema_name, schema_name, schema_scopes, validate};
+
+    #[test]
+    fn applicable_schema_names() {
+        let names: Vec<String> = schema_scopes(&"team/svc/prod".parse().unwrap())
+            .iter()
+            .map(|scope| schema_name(scope).unwrap().into())
+            .collect();
+        assert_eq!(
+            names,
+            ["team.schema", "team/svc.schema", "team/svc/prod.schema"]
+        );
+
+        for name in ["team/svc.schema", "team.schema/svc"] {
+            assert!(is_schema_name(&name.parse().unwrap()));
+        }
+        assert!(!is_schema_name(&"team/schema".parse().unwrap()));
+    }
+
+    #[test]
+    fn validation_errors() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "port": { "type": "integer" },
+                "db": {
+                    "type": "object",
+                    "properties": { "host": { "type": "string" } },
+                    "required": ["host", "password"],
+                },
+            },
+            "required": ["port", "db"],
+        });
+        let schemas = [("team".parse().unwrap(), schema)];
+
+        assert!(validate(
+            &schemas,
+            &json!({ "port": 80, "db": { "host": "x", "password": "y" } }),
+            false
+        )
+        .is_ok());
+
+        // Every error is reported, with a path to the offending value.
+        let err = validate(
+            &schemas,
+            &json!({ "port": "80", "db": { "host": 1 } }),
+            false,
+        )
+        .unwrap_err();
+        let mut paths: Vec<_> = err.errors.iter().map(|e| e.path.as_str()).collect();
+        paths.sort_unstable();
+        assert_eq!(paths, ["/db", "/db/host", "/port"]);
+
+        // Templated keys satisfy required properties, but are not checked.
+        let template = json!({ "port": 80, "db": { "host": "x", "*password": "aws:db" } });
+        assert!(validate(&schemas, &template, true).is_ok());
+        assert!(validate(&schemas, &template, false).is_err());
+
+        let env = "team".parse().unwrap();
+        assert!(check_schema(&env, &json!({ "type": 5 })).is_err());
+        assert!(check_schema(&env, &json!({ "type": "object" })).is_ok());
+    }
+}
@@ -0,0 +1,465 @@
+//! Storage of templates in a SQL database, either SQLite or Postgres.
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::any::{AnyKind, AnyPool, AnyPoolOptions};
+use sqlx::Row;
+use tokio::sync::broadcast;
+use tracing::info;
+#[cfg(feature = "postgres")]
+use tracing::warn;
+
+use super::name::EnvName;
+use super::storage::{Revision, TemplateNotFound, TemplateStore, VersionConflict};
+
+/// Postgres channel used to notify other processes of template changes.
+#[cfg(feature = "postgres")]
+const CHANNEL: &str = "cadre_templates";
+
+/// Number of times an unconditional write is attempted when it races with
+/// other writers.
+const WRITE_ATTEMPTS: u32 = 3;
+
+/// Statements that create the database schema, if it does not exist yet.
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS cadre_templates (
+        env TEXT PRIMARY KEY,
+        body TEXT NOT NULL,
+        version BIGINT NOT NULL,
+        updated_at BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS cadre_revisions (
+        env TEXT NOT NULL,
+        version BIGINT NOT NULL,
+        body TEXT NOT NULL,
+        created_at BIGINT NOT NULL,
+        PRIMARY KEY (env, version)
+    )",
+    "CREATE TABLE IF NOT EXISTS cadre_audit (
+        env TEXT NOT NULL,
+        action TEXT NOT NULL,
+        version BIGINT NOT NULL,
+        created_at BIGINT NOT NULL
+    )",
+];
+
+/// A SQL database holding templates, their revisions and an audit log.
+///
+/// Every template has a version number that increases by one on each write.
+/// Writes are transactional, and can be made conditional on the current
+/// version to safely support multiple writers.
+#[derive(Debug)]
+pub struct SqlStore {
+    pool: AnyPool,
+    changes: broadcast::Sender<EnvName>,
+}
+
+impl SqlStore {
+    /// Connect to a database by URL, creating the schema if needed.
+    ///
+    /// For Postgres databases, this also listens for changes made by other
+    /// processes, which must be called from within a Tokio runtime.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let pool = AnyPoolOptions::new().connect(url).await?;
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+        let (changes, _) = broadcast::channel(256);
+
+        #[cfg(feature = "postgres")]
+        if pool.any_kind() == AnyKind::Postgres {
+            let mut listener = sqlx::postgres::PgListener::connect(url).await?;
+            listener.listen(CHANNEL).await?;
+            let changes = changes.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.recv().await {
+                        Ok(notification) => {
+                            if let Ok(env) = notification.payload().parse() {
+                                let _ = changes.send(env);
+                            }
+                        }
+                        Err(err) => warn!(?err, "problem receiving template changes"),
+                    }
+                }
+            });
+        }
+
+        Ok(Self { pool, changes })
+    }
+
+    /// Return the kind of database this is connected to.
+    pub fn kind(&self) -> AnyKind {
+        self.pool.any_kind()
+    }
+
+    /// Write a new version of a template, or remove it if `value` is `None`,
+    /// recording the change in the audit log.
+    ///
+    /// If `expected` is given, the change only succeeds if it matches the
+    /// current version of the template. Otherwise, writes that lose a race
+    /// with another writer are retried.
+    async fn write(
+        &self,
+        env: &EnvName,
+        value: Option<&Value>,
+        expected: Option<&str>,
+    ) -> Result<()> {
+        let mut attempts = 1;
+        loop {
+            match self.try_write(env, value, expected).await {
+                Err(err) if self.is_write_conflict(&err) => {
+                    if let Some(expected) = expected {
+                        // Another writer got there first, so the version we
+                        // checked is no longer current.
+                        let actual = self.version(env).await?;
+                        return Err(VersionConflict {
+                            expected: expected.into(),
+                            actual,
+                        }
+                        .into());
+                    }
+                    if attempts == WRITE_ATTEMPTS {
+                        return Err(err);
+                    }
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Return the current version of a template, if it exists.
+    async fn version(&self, env: &EnvName) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT version FROM cadre_templates WHERE env = $1")
+            .bind(env.as_str())
+            .fetch_optional(&self.pool)
+            .await?;
+        let version = row.map(|row| row.try_get::<i64, _>("version"));
+        Ok(version.transpose()?.map(|version| version.to_string()))
+    }
+
+    /// Check if an error was caused by a concurrent write to the same
+    /// template, which either violates the primary key of the revisions table
+    /// or, on SQLite, fails to take the database lock.
+    fn is_write_conflict(&self, err: &anyhow::Error) -> bool {
+        let code = match err
+            .downcast_ref::<sqlx::Error>()
+            .and_then(|err| err.as_database_error())
+            .and_then(|err| err.code())
+        {
+            Some(code) => code,
+            None => return false,
+        };
+        match self.kind() {
+            // Extended result codes keep the primary code in the low byte:
+            // SQLITE_BUSY, SQLITE_LOCKED or SQLITE_CONSTRAINT.
+            AnyKind::Sqlite => code
+                .parse::<i32>()
+                .map(|code| matches!(code & 0xff, 5 | 6 | 19))
+                .unwrap_or(false),
+            // Unique violation, serialization failure or deadlock.
+            #[cfg(feature = "postgres")]
+            AnyKind::Postgres => matches!(&*code, "23505" | "40001" | "40P01"),
+        }
+    }
+
+    /// Make a single attempt at [`SqlStore::write`], in one transaction.
+    async fn try_write(
+        &self,
+        env: &EnvName,
+        value: Option<&Value>,
+        expected: Option<&str>,
+    ) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        let mut tx = self.pool.begin().await?;
+        let current: Option<i64> =
+            sqlx::query("SELECT version FROM cadre_templates WHERE env = $1")
+                .bind(env.as_str())
+                .fetch_optional(&mut tx)
+                .await?
+                .map(|row| row.try_get("version"))
+                .transpose()?;
+        if let Some(expected) = expected {
+            let actual = current.map(|version| version.to_string());
+            if actual.as_deref() != Some(expected) {
+                return Err(VersionConflict {
+                    expected: expected.into(),
+                    actual,
+                }
+                .into());
+            }
+        }
+
+        let version = match value {
+            Some(value) => {
+                // Versions keep counting up from the last revision, even if
+                // the template was removed in between.
+                let body = serde_json::to_string_pretty(value)?;
+                let row = sqlx::query(
+                    "SELECT COALESCE(MAX(version), 0) AS version FROM cadre_revisions
+                    WHERE env = $1",
+                )
+                .bind(env.as_str())
+                .fetch_one(&mut tx)
+                .await?;
+                let version = row.try_get::<i64, _>("version")? + 1;
+
+                // Concurrent writers race on the primary key of the revisions
+                // table, so only one of them can create each version.
+                sqlx::query(
+                    "INSERT INTO cadre_revisions (env, version, body, created_at)
+                    VALUES ($1, $2, $3, $4)",
+                )
+                .bind(env.as_str())
+                .bind(version)
+                .bind(&body)
+                .bind(now)
+                .execute(&mut tx)
+                .await?;
+                sqlx::query(
+                    "INSERT INTO cadre_templates (env, body, version, updated_at)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (env) DO UPDATE SET
+                        body = excluded.body,
+                        version = excluded.version,
+                        updated_at = excluded.updated_at",
+                )
+                .bind(env.as_str())
+                .bind(&body)
+                .bind(version)
+                .bind(now)
+                .execute(&mut tx)
+                .await?;
+                version
+            }
+            None => match current {
+                Some(version) => {
+                    sqlx::query("DELETE FROM cadre_templates WHERE env = $1")
+                        .bind(env.as_str())
+                        .execute(&mut tx)
+                        .await?;
+                    version
+                }
+                None => return Ok(()),
+            },
+        };
+
+        let action = if value.is_some() { "write" } else { "delete" };
+        sqlx::query(
+            "INSERT INTO cadre_audit (env, action, version, created_at)
+            VALUES ($1, $2, $3, $4)",
+        )
+        .bind(env.as_str())
+        .bind(action)
+        .bind(version)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        #[cfg(feature = "postgres")]
+        if self.pool.any_kind() == AnyKind::Postgres {
+            // Notifications are only delivered once the transaction commits.
+            sqlx::query("SELECT pg_notify($1, $2)")
+                .bind(CHANNEL)
+                .bind(env.as_str())
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        let _ = self.changes.send(env.clone());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TemplateStore for SqlStore {
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, env: &EnvName) -> Result<Value> {
+        info!("reading template");
+        let row = sqlx::query("SELECT body FROM cadre_templates WHERE env = $1")
+            .bind(env.as_str())
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| TemplateNotFound(env.clone()))?;
+        Ok(serde_json::from_str(row.try_get("body")?)?)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        info!("writing template");
+        self.write(env, Some(value), None).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete(&self, env: &EnvName) -> Result<()> {
+        info!("deleting template");
+        self.write(env, None, None).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        info!("listing template versions");
+        let rows = sqlx::query("SELECT env, version FROM cadre_templates")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut results = HashMap::new();
+        for row in rows {
+            let env: String = row.try_get("env")?;
+            let version: i64 = row.try_get("version")?;
+            if let Ok(env) = env.parse() {
+                results.insert(env, version.to_string());
+            }
+        }
+        Ok(results)
+    }
+
+    /// Subscribe to the names of templates as they are changed, including by
+    /// other processes sharing a Postgres database.
+    fn watch(&self) -> Option<broadcast::Receiver<EnvName>> {
+        Some(self.changes.subscribe())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn compare_and_set(&self, env: &EnvName, value: &Value, expected: &str) -> Result<()> {
+        info!("conditionally writing template");
+        self.write(env, Some(value), Some(expected)).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+        info!("listing template history");
+        let rows = sqlx::query(
+            "SELECT version, created_at FROM cadre_revisions
+            WHERE env = $1 ORDER BY version DESC",
+        )
+        .bind(env.as_str())
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(Revision {
+                    id: row.try_get::<i64, _>("version")?.to_string(),
+                    author: None,
+                    message: None,
+                    timestamp: row.try_get("created_at")?,
+                })
+            })
+            .collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+        info!("reading template revision");
+        let version: i64 = id.parse().context("invalid revision")?;
+        let row = sqlx::query("SELECT body FROM cadre_revisions WHERE env = $1 AND version = $2")
+            .bind(env.as_str())
+            .bind(version)
+            .fetch_optional(&self.pool)
+            .await?
+            .context("missing template revision")?;
+        Ok(serde_json::from_str(row.try_get("body")?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use serde_json::json;
+
+    use super::SqlStore;
+    use crate::server::storage::{TemplateStore, VersionConflict};
+
+    async fn sqlite_store() -> Result<(SqlStore, tempfile::TempDir)> {
+        let dir = tempfile::tempdir()?;
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("cadre.db").display()
+        );
+        Ok((SqlStore::connect(&url).await?, dir))
+    }
+
+    #[tokio::test]
+    async fn sqlite_operations() -> Result<()> {
+        let (store, _dir) = sqlite_store().await?;
+        let hello = "hello".parse()?;
+        let mut changes = store.watch().unwrap();
+
+        assert!(store.get(&hello).await.is_err());
+        assert!(store.versions().await?.is_empty());
+
+        store.set(&hello, &json!({ "a": 1 })).await?;
+        store.set(&hello, &json!({ "a": 2 })).await?;
+        assert_eq!(store.get(&hello).await?, json!({ "a": 2 }));
+        assert_eq!(store.versions().await?[&hello], "2");
+        assert_eq!(changes.recv().await?, hello);
+
+        let history = store.history(&hello).await?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].id, "2");
+        assert_eq!(store.get_revision(&hello, "1").await?, json!({ "a": 1 }));
+
+        // Removing a template keeps its history, and numbering continues.
+        store.delete(&hello).await?;
+        assert!(store.get(&hello).await.is_err());
+        store.set(&hello, &json!({ "a": 3 })).await?;
+        assert_eq!(store.versions().await?[&hello], "3");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap() -> Result<()> {
+        let (store, _dir) = sqlite_store().await?;
+        let hello = "hello".parse()?;
+
+        // A template that does not exist yet has no version to match.
+        let err = store.compare_and_set(&hello, &json!(1), "1").await;
+        assert!(err.unwrap_err().downcast_ref::<VersionConflict>().is_some());
+
+        store.set(&hello, &json!(1)).await?;
+        store.compare_and_set(&hello, &json!(2), "1").await?;
+        let err = store
+            .compare_and_set(&hello, &json!(3), "1")
+            .await
+            .unwrap_err();
+        let conflict = err.downcast::<VersionConflict>()?;
+        assert_eq!(conflict.actual.as_deref(), Some("2"));
+        assert_eq!(store.get(&hello).await?, json!(2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_writers() -> Result<()> {
+        let (store, _dir) = sqlite_store().await?;
+        let hello = "hello".parse()?;
+        store.set(&hello, &json!(0)).await?;
+
+        // Only one conditional writer can win, and the others see a conflict.
+        let values = [json!(1), json!(2), json!(3), json!(4)];
+        let results = tokio::join!(
+            store.compare_and_set(&hello, &values[0], "1"),
+            store.compare_and_set(&hello, &values[1], "1"),
+            store.compare_and_set(&hello, &values[2], "1"),
+            store.compare_and_set(&hello, &values[3], "1"),
+        );
+        let mut succeeded = 0;
+        for result in [results.0, results.1, results.2, results.3] {
+            match result {
+                Ok(()) => succeeded += 1,
+                Err(err) => assert!(err.is::<VersionConflict>(), "{err:?}"),
+            }
+        }
+        assert_eq!(succeeded, 1);
+        assert_eq!(store.versions().await?[&hello], "2");
+
+        Ok(())
+    }
+}
@@ -1,64 +1,642 @@
 //! Server state object managing all operations on cadre configuration.
 
-use std::str;
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 
-use anyhow::Result;
-use serde_json::Value;
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{self, Duration};
+use tracing::warn;
 
+use super::cache::TimedCache;
+use super::crypto::random_key;
+use super::mirror::Mirror;
+use super::name::EnvName;
 use super::resolver::ResolverChain;
-use super::storage::Storage;
-use super::template::{merge_templates, populate_template};
+use super::schema::{self, is_schema_name, schema_name};
+use super::storage::{Revision, TemplateNotFound, TemplateStore};
+use super::template::{
+    find_origins, merge_templates, populate_template_tracked, redact_secrets, Resolution,
+};
+
+/// Longest time, in seconds, that a populated config is cached for.
+///
+/// This applies when no resolver in the chain bounds the cache lifetime. It
+/// limits how long writes made through other replicas can go unnoticed.
+pub const MAX_CONFIG_TTL: f64 = 60.0;
 
 /// Object that manages server state, including storage and templating.
 #[derive(Clone)]
 pub struct State {
     chain: Arc<ResolverChain>,
-    storage: Arc<Storage>,
-    default_template: Option<String>,
+    storage: Arc<dyn TemplateStore>,
+    default_template: Option<EnvName>,
+    configs: Arc<ConfigCache>,
+    mirror: Option<Arc<Mirror>>,
+    validate_configs: bool,
+    redaction_key: [u8; 32],
+}
+
+/// Directory-style listing of the contents of a namespace.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Listing {
+    /// Namespaces nested directly under this one.
+    pub namespaces: Vec<EnvName>,
+
+    /// Configuration environments directly in this namespace.
+    pub configs: Vec<EnvName>,
+}
+
+/// A populated configuration, annotated with where each value came from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// The populated configuration.
+    pub config: Value,
+
+    /// Source of each leaf value in the configuration, keyed by JSON Pointer.
+    pub sources: BTreeMap<String, Source>,
+}
+
+/// Where a value in a populated configuration came from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    /// Template that defined the value.
+    pub env: EnvName,
+
+    /// JSON Pointer to the key that defined the value in that template.
+    pub path: String,
+
+    /// Templated string that produced the value, if it was resolved.
+    pub resolver: Option<String>,
+}
+
+/// A template populated on its own, before merging with others.
+#[derive(Clone)]
+struct Layer {
+    env: EnvName,
+    value: Value,
+    resolutions: Vec<Resolution>,
+}
+
+/// A populated configuration, along with a copy with secrets redacted.
+#[derive(Clone)]
+struct Populated {
+    config: Value,
+    redacted: Value,
+}
+
+/// Cache of populated configurations, keyed by environment.
+struct ConfigCache {
+    cache: Option<TimedCache<EnvName, Populated>>,
+    generation: AtomicU64,
 }
 
 impl State {
-    /// Create a new state object.
-    pub fn new(chain: ResolverChain, storage: Storage, default_template: Option<&str>) -> Self {
+    /// Create a new state object, persisting templates to any store.
+    pub fn new(
+        chain: ResolverChain,
+        storage: impl TemplateStore + 'static,
+        default_template: Option<EnvName>,
+    ) -> Self {
+        let ttl = chain
+            .max_age()
+            .unwrap_or(MAX_CONFIG_TTL)
+            .min(MAX_CONFIG_TTL);
+        let cache = (ttl > 0.0).then(|| TimedCache::new(0.75 * ttl, ttl));
         Self {
             chain: Arc::new(chain),
             storage: Arc::new(storage),
-            default_template: default_template.map(String::from),
+            default_template,
+            configs: Arc::new(ConfigCache {
+                cache,
+                generation: Default::default(),
+            }),
+            mirror: None,
+            validate_configs: false,
+            redaction_key: random_key(),
         }
     }
 
+    /// Also validate populated configs against their schemas when they are
+    /// loaded, in addition to validating templates when they are written.
+    ///
+    /// This catches resolved values that do not match the schema, such as a
+    /// secret holding a string where a number is expected.
+    pub fn with_config_validation(mut self) -> Self {
+        self.validate_configs = true;
+        self
+    }
+
+    /// Set the key that fingerprints of redacted secrets are computed with.
+    ///
+    /// By default, a random key is generated for each process, so
+    /// fingerprints only stay the same until restart and differ between
+    /// replicas.
+    pub fn with_redaction_key(mut self, key: [u8; 32]) -> Self {
+        self.redaction_key = key;
+        self
+    }
+
+    /// Keep an in-memory mirror of all templates, so that reads do not need
+    /// to go to storage.
+    ///
+    /// The mirror is loaded from storage immediately, then refreshed every
+    /// `interval` by a background task to pick up writes made elsewhere. If
+    /// the storage backend sends change notifications, the mirror is also
+    /// refreshed as soon as one arrives. The task stops once every clone of
+    /// this state object is dropped.
+    pub async fn with_mirror(mut self, interval: Duration) -> Result<Self> {
+        let changes = self.storage.watch();
+        let mirror = Arc::new(Mirror::load(&*self.storage).await?);
+        tokio::spawn(sync_mirror(
+            Arc::downgrade(&mirror),
+            Arc::clone(&self.storage),
+            Arc::clone(&self.configs),
+            self.default_template.clone(),
+            interval,
+            changes,
+        ));
+        self.mirror = Some(mirror);
+        Ok(self)
+    }
+
     /// Read a configuration template from S3.
-    pub async fn read_template(&self, env: &str) -> Result<Value> {
-        self.storage.get(env).await
+    pub async fn read_template(&self, env: &EnvName) -> Result<Value> {
+        if let Some(mirror) = &self.mirror {
+            if let Some(value) = mirror.get(env) {
+                return Ok(value);
+            }
+        }
+        let value = self.storage.get(env).await?;
+        if let Some(mirror) = &self.mirror {
+            mirror.insert(env, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Read a past revision of a configuration template.
+    pub async fn read_template_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+        self.storage.get_revision(env, id).await
+    }
+
+    /// List past revisions of a configuration template, most recent first.
+    pub async fn template_history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+        self.storage.history(env).await
     }
 
     /// Atomically persist a configuration template to S3.
-    pub async fn write_template(&self, env: &str, template: &Value) -> Result<()> {
-        self.storage.set(env, template).await
+    ///
+    /// Fails with a [`SchemaViolation`](super::schema::SchemaViolation) error
+    /// if the template does not match the schemas that apply to it.
+    pub async fn write_template(&self, env: &EnvName, template: &Value) -> Result<()> {
+        self.check_template(env, template).await?;
+        self.storage.set(env, template).await?;
+        if let Some(mirror) = &self.mirror {
+            mirror.insert(env, template.clone());
+        }
+        self.configs.invalidate(env, self.default_template.as_ref());
+        Ok(())
+    }
+
+    /// Persist a configuration template, only if it is currently at the
+    /// `expected` version.
+    ///
+    /// Fails with a [`VersionConflict`](super::storage::VersionConflict) error
+    /// if the template was changed since that version.
+    pub async fn write_template_if(
+        &self,
+        env: &EnvName,
+        template: &Value,
+        expected: &str,
+    ) -> Result<()> {
+        self.check_template(env, template).await?;
+        self.storage
+            .compare_and_set(env, template, expected)
+            .await?;
+        if let Some(mirror) = &self.mirror {
+            mirror.insert(env, template.clone());
+        }
+        self.configs.invalidate(env, self.default_template.as_ref());
+        Ok(())
+    }
+
+    /// Remove a configuration template from storage.
+    pub async fn delete_template(&self, env: &EnvName) -> Result<()> {
+        ensure!(!is_schema_name(env), "{env:?} is a schema, not a template");
+        self.storage.delete(env).await?;
+        if let Some(mirror) = &self.mirror {
+            mirror.remove(env);
+        }
+        self.configs.invalidate(env, self.default_template.as_ref());
+        Ok(())
     }
 
     /// Read a configuration template from S3 and populate templated values.
     ///
     /// This configuration will first be merged with the default template as
-    /// well, if it is provided.
-    pub async fn load_config(&self, env: &str) -> Result<Value> {
-        let mut template = self.read_template(env).await?;
-        populate_template(&mut template, &self.chain).await?;
+    /// well, if it is provided. Populated configurations are cached until the
+    /// template or the default template is written.
+    pub async fn load_config(&self, env: &EnvName) -> Result<Value> {
+        Ok(self.populated(env).await?.config)
+    }
+
+    /// Load a configuration like [`State::load_config`], but with every value
+    /// produced by a secret resolver replaced by a redacted placeholder.
+    pub async fn load_redacted_config(&self, env: &EnvName) -> Result<Value> {
+        Ok(self.populated(env).await?.redacted)
+    }
+
+    /// Load several configurations at once, like [`State::load_config`], or
+    /// like [`State::load_redacted_config`] if `redact` is true.
+    ///
+    /// Cached configs are reused, and the rest share a single read and
+    /// population of the default template. Fails if any config fails to load.
+    pub async fn load_configs(
+        &self,
+        envs: &[EnvName],
+        redact: bool,
+    ) -> Result<BTreeMap<EnvName, Value>> {
+        let generation = self.configs.generation.load(Ordering::SeqCst);
+        let mut default_layer = None;
+        let mut configs = BTreeMap::new();
+        for env in envs {
+            let populated = match self.configs.get(env) {
+                Some(populated) => populated,
+                None => {
+                    if default_layer.is_none() {
+                        default_layer = Some(self.default_layer().await?);
+                    }
+                    let template = self.read_template(env).await?;
+                    let mut layers = vec![self.populate_layer(env, template).await?];
+                    match default_layer.as_ref().and_then(Option::as_ref) {
+                        Some(layer) if &layer.env != env => layers.push(layer.clone()),
+                        _ => (),
+                    }
+                    let populated = self.merge_layers(env, layers).await?;
+                    self.configs.insert(env, &populated, generation);
+                    populated
+                }
+            };
+            let config = match redact {
+                true => populated.redacted,
+                false => populated.config,
+            };
+            configs.insert(env.clone(), config);
+        }
+        Ok(configs)
+    }
+
+    /// Return the config that an environment would have with a candidate
+    /// template, without persisting or caching anything.
+    ///
+    /// This runs exactly the same steps as [`State::load_config`], so it fails
+    /// in the same ways, such as with a
+    /// [`PopulateFailure`](super::template::PopulateFailure) listing every
+    /// templated value that could not be resolved. Secret values are redacted
+    /// if `redact` is true.
+    pub async fn preview_config(
+        &self,
+        env: &EnvName,
+        template: &Value,
+        redact: bool,
+    ) -> Result<Value> {
+        let populated = self.build_config(env, template.clone()).await?;
+        Ok(match redact {
+            true => populated.redacted,
+            false => populated.config,
+        })
+    }
+
+    /// Load a populated config, from the cache if possible.
+    async fn populated(&self, env: &EnvName) -> Result<Populated> {
+        if let Some(populated) = self.configs.get(env) {
+            return Ok(populated);
+        }
+
+        let generation = self.configs.generation.load(Ordering::SeqCst);
+        let template = self.read_template(env).await?;
+        let populated = self.build_config(env, template).await?;
+
+        self.configs.insert(env, &populated, generation);
+        Ok(populated)
+    }
+
+    /// Explain where each value in a populated config came from.
+    ///
+    /// The config is built and validated exactly as in
+    /// [`State::load_config`], but is not cached, and secret values are
+    /// redacted if `redact` is true.
+    pub async fn explain_config(&self, env: &EnvName, redact: bool) -> Result<Explanation> {
+        let template = self.read_template(env).await?;
+        let layers = self.populate_layers(env, template).await?;
+
+        // Validation needs the secret values, even if they are not shown.
+        let (mut config, mut unredacted) = (None, None);
+        let mut origins = Vec::new();
+        for layer in layers {
+            let mut value = layer.value;
+            if redact {
+                if self.validate_configs {
+                    merge_layer(&mut unredacted, value.clone());
+                }
+                redact_secrets(&mut value, &layer.resolutions, &self.redaction_key);
+            }
+            origins.push((layer.env, find_origins(&value, &layer.resolutions)));
+            merge_layer(&mut config, value);
+        }
+        let config = config.unwrap_or_default();
+        if self.validate_configs {
+            let schemas = self.read_schemas(env).await?;
+            schema::validate(&schemas, unredacted.as_ref().unwrap_or(&config), false)?;
+        }
+
+        // Each leaf of the merged config comes from the first layer with it.
+        let mut sources = BTreeMap::new();
+        for (env, origins) in origins {
+            for (path, origin) in origins {
+                let leaf = match config.pointer(&path) {
+                    Some(Value::Object(map)) => map.is_empty(),
+                    Some(_) => true,
+                    None => false,
+                };
+                if leaf && !sources.contains_key(&path) {
+                    let source = Source {
+                        env: env.clone(),
+                        path: origin.path,
+                        resolver: origin.resolver,
+                    };
+                    sources.insert(path, source);
+                }
+            }
+        }
+        Ok(Explanation { config, sources })
+    }
+
+    /// Populate a template, merge it with the default template, and validate
+    /// the result if enabled.
+    async fn build_config(&self, env: &EnvName, template: Value) -> Result<Populated> {
+        let layers = self.populate_layers(env, template).await?;
+        self.merge_layers(env, layers).await
+    }
+
+    /// Merge populated layers into a config, validating it if enabled.
+    async fn merge_layers(&self, env: &EnvName, layers: Vec<Layer>) -> Result<Populated> {
+        let (mut config, mut redacted) = (None, None);
+        for layer in layers {
+            let mut value = layer.value;
+            merge_layer(&mut config, value.clone());
+            redact_secrets(&mut value, &layer.resolutions, &self.redaction_key);
+            merge_layer(&mut redacted, value);
+        }
+        let (config, redacted) = (config.unwrap_or_default(), redacted.unwrap_or_default());
+
+        if self.validate_configs {
+            let schemas = self.read_schemas(env).await?;
+            schema::validate(&schemas, &config, false)?;
+        }
+        Ok(Populated { config, redacted })
+    }
+
+    /// Populate a template and the default template that it builds upon, in
+    /// order of precedence.
+    async fn populate_layers(&self, env: &EnvName, template: Value) -> Result<Vec<Layer>> {
+        let mut layers = vec![self.populate_layer(env, template).await?];
+        if self.default_template.as_ref() != Some(env) {
+            layers.extend(self.default_layer().await?);
+        }
+        Ok(layers)
+    }
+
+    /// Read and populate the default template, if there is one.
+    async fn default_layer(&self) -> Result<Option<Layer>> {
+        match &self.default_template {
+            Some(default_env) => {
+                let template = self.read_template(default_env).await?;
+                Ok(Some(self.populate_layer(default_env, template).await?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn populate_layer(&self, env: &EnvName, mut value: Value) -> Result<Layer> {
+        let resolutions = populate_template_tracked(&mut value, &self.chain).await?;
+        Ok(Layer {
+            env: env.clone(),
+            value,
+            resolutions,
+        })
+    }
+
+    /// Read the JSON Schema for an environment or namespace.
+    pub async fn read_schema(&self, env: &EnvName) -> Result<Value> {
+        self.read_template(&schema_name(env)?).await
+    }
+
+    /// Persist the JSON Schema for an environment or namespace.
+    ///
+    /// Existing templates are not checked against the new schema, only those
+    /// written after it.
+    pub async fn write_schema(&self, env: &EnvName, schema: &Value) -> Result<()> {
+        schema::check_schema(env, schema)?;
+        let name = schema_name(env)?;
+        self.storage.set(&name, schema).await?;
+        if let Some(mirror) = &self.mirror {
+            mirror.insert(&name, schema.clone());
+        }
+        self.configs.clear();
+        Ok(())
+    }
+
+    /// Remove the JSON Schema for an environment or namespace.
+    pub async fn delete_schema(&self, env: &EnvName) -> Result<()> {
+        let name = schema_name(env)?;
+        self.storage.delete(&name).await?;
+        if let Some(mirror) = &self.mirror {
+            mirror.remove(&name);
+        }
+        self.configs.clear();
+        Ok(())
+    }
+
+    /// Return a single schema combining every schema that applies to an
+    /// environment, which accepts anything if there are none.
+    pub async fn effective_schema(&self, env: &EnvName) -> Result<Value> {
+        let mut schemas = self.read_schemas(env).await?;
+        Ok(match schemas.len() {
+            0 => json!({}),
+            1 => schemas.pop().unwrap().1,
+            _ => json!({ "allOf": schemas.into_iter().map(|(_, s)| s).collect::<Vec<_>>() }),
+        })
+    }
+
+    /// Read every stored schema that applies to an environment, paired with
+    /// the environment or namespace it belongs to.
+    ///
+    /// Scopes whose schema name would be too long cannot have a schema, so
+    /// they are skipped. With a mirror, which holds every template, schemas
+    /// missing from it are not looked up in storage.
+    async fn read_schemas(&self, env: &EnvName) -> Result<Vec<(EnvName, Value)>> {
+        let mut schemas = Vec::new();
+        for scope in schema::schema_scopes(env) {
+            let name = match schema_name(&scope) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let stored = match &self.mirror {
+                Some(mirror) => mirror.get(&name),
+                None => match self.storage.get(&name).await {
+                    Ok(schema) => Some(schema),
+                    Err(err) if err.is::<TemplateNotFound>() => None,
+                    Err(err) => return Err(err),
+                },
+            };
+            if let Some(schema) = stored {
+                schemas.push((scope, schema));
+            }
+        }
+        Ok(schemas)
+    }
+
+    /// Check a template against its schemas before it is written.
+    ///
+    /// The template is merged with the default template first, so that
+    /// properties required by a schema may be inherited from it.
+    async fn check_template(&self, env: &EnvName, template: &Value) -> Result<()> {
+        ensure!(!is_schema_name(env), "{env:?} is a schema, not a template");
+        let schemas = self.read_schemas(env).await?;
+        if schemas.is_empty() {
+            return Ok(());
+        }
+        let mut merged = template.clone();
         if let Some(default_env) = &self.default_template {
             if env != default_env {
-                let mut default_template = self.read_template(default_env).await?;
-                populate_template(&mut default_template, &self.chain).await?;
-                merge_templates(&mut template, &default_template)
+                if let Ok(default_template) = self.read_template(default_env).await {
+                    merge_templates(&mut merged, &default_template);
+                }
             }
         }
-        Ok(template)
+        schema::validate(&schemas, &merged, true)?;
+        Ok(())
+    }
+
+    /// Return the names of everything in storage, including schemas.
+    async fn list_stored(&self) -> Result<Vec<EnvName>> {
+        match &self.mirror {
+            Some(mirror) => Ok(mirror.list()),
+            None => self.storage.list().await,
+        }
     }
 
     /// Return a list of available configuration templates from S3.
-    pub async fn list_configs(&self) -> Result<Vec<String>> {
-        let mut templates = self.storage.list().await?;
+    pub async fn list_configs(&self) -> Result<Vec<EnvName>> {
+        let mut templates = self.list_stored().await?;
+        templates.retain(|env| !is_schema_name(env));
         templates.sort_unstable();
         Ok(templates)
     }
+
+    /// List the configuration templates and namespaces directly inside a
+    /// namespace, or at the root if `namespace` is `None`.
+    pub async fn list_namespace(&self, namespace: Option<&EnvName>) -> Result<Listing> {
+        let mut listing = Listing::default();
+        for env in self.list_configs().await? {
+            match env.child_of(namespace) {
+                Some((child, true)) => listing.configs.push(child),
+                Some((child, false)) => listing.namespaces.push(child),
+                None => (),
+            }
+        }
+        listing.namespaces.dedup();
+        Ok(listing)
+    }
+}
+
+impl ConfigCache {
+    fn get(&self, env: &EnvName) -> Option<Populated> {
+        self.cache.as_ref()?.get(env)
+    }
+
+    /// Cache a populated config, unless a write happened since `generation`
+    /// was read, since it may have been built from an outdated template.
+    fn insert(&self, env: &EnvName, value: &Populated, generation: u64) {
+        if let Some(cache) = &self.cache {
+            if self.generation.load(Ordering::SeqCst) == generation {
+                cache.insert(env.clone(), value.clone());
+            }
+        }
+    }
+
+    /// Drop all cached configurations.
+    fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    /// Drop cached configurations that depend on the given template.
+    fn invalidate(&self, env: &EnvName, default_template: Option<&EnvName>) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        if let Some(cache) = &self.cache {
+            if default_template == Some(env) {
+                cache.clear();
+            } else {
+                cache.remove(env);
+            }
+        }
+    }
+}
+
+/// Merge a populated template into a config, under the values already there.
+fn merge_layer(config: &mut Option<Value>, value: Value) {
+    match config {
+        Some(config) => merge_templates(config, &value),
+        None => *config = Some(value),
+    }
+}
+
+/// Background task that brings a template mirror up to date, periodically and
+/// whenever storage reports a change.
+async fn sync_mirror(
+    mirror: Weak<Mirror>,
+    storage: Arc<dyn TemplateStore>,
+    configs: Arc<ConfigCache>,
+    default_template: Option<EnvName>,
+    interval: Duration,
+    mut changes: Option<broadcast::Receiver<EnvName>>,
+) {
+    let mut interval = time::interval(interval);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        match &mut changes {
+            Some(receiver) => tokio::select! {
+                _ = interval.tick() => (),
+                result = receiver.recv() => {
+                    // A lagged receiver has missed changes, so it syncs too.
+                    if let Err(RecvError::Closed) = result {
+                        changes = None;
+                    }
+                }
+            },
+            None => {
+                interval.tick().await;
+            }
+        }
+        let mirror = match mirror.upgrade() {
+            Some(mirror) => mirror,
+            None => break,
+        };
+        match mirror.sync(&*storage).await {
+            Ok(changed) => {
+                for env in changed {
+                    configs.invalidate(&env, default_template.as_ref());
+                }
+            }
+            Err(err) => warn!(?err, "problem syncing template mirror"),
+        }
+    }
 }
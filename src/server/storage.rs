@@ -1,125 +1,733 @@
-//! Pluggable storage persistence backend for templates.
+//! Pluggable storage persistence backends for templates.
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::model::{Object, ServerSideEncryption, StorageClass};
+use aws_sdk_s3::types::SdkError;
+use aws_sdk_s3::Endpoint;
+use aws_types::sdk_config::SdkConfig;
+use axum::http::Uri;
+use fs2::FileExt;
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::fs;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::sync::broadcast;
+use tokio::{fs, task};
 use tracing::info;
 
-/// Storage backend specification.
-#[derive(Debug)]
-pub enum Storage {
-    /// Persist templates to an S3 bucket.
-    S3(aws_sdk_s3::Client, String),
+use super::name::EnvName;
 
-    /// Persist templates to the local file system.
-    LocalFS(PathBuf),
+/// Trait for persisting configuration templates.
+///
+/// Stores are shared between requests, so every method takes `&self`. Only
+/// the basic operations are required; revision history, conditional writes and
+/// change notifications are optional, and are reported as unsupported by the
+/// default implementations.
+#[async_trait]
+pub trait TemplateStore: Send + Sync {
+    /// Retrieve the current version of a template.
+    ///
+    /// Fails with a [`TemplateNotFound`] error if the template does not exist.
+    async fn get(&self, env: &EnvName) -> Result<Value>;
 
-    /// Only store data in-memory.
-    Memory(Mutex<HashMap<String, Value>>),
+    /// Write a new version of a template, creating it if needed.
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()>;
+
+    /// Remove a template. Removing a template that does not exist is not an
+    /// error.
+    async fn delete(&self, env: &EnvName) -> Result<()>;
+
+    /// List all templates, along with an opaque version tag for each one that
+    /// changes whenever the template is written.
+    async fn versions(&self) -> Result<HashMap<EnvName, String>>;
+
+    /// List the names of all templates.
+    async fn list(&self) -> Result<Vec<EnvName>> {
+        Ok(self.versions().await?.into_keys().collect())
+    }
+
+    /// Subscribe to the names of templates as they are written or removed.
+    ///
+    /// Returns `None` if the store does not send notifications, in which case
+    /// changes can only be found by polling [`TemplateStore::versions`].
+    fn watch(&self) -> Option<broadcast::Receiver<EnvName>> {
+        None
+    }
+
+    /// Write a template, only if it is currently at the `expected` version
+    /// tag, as returned by [`TemplateStore::versions`].
+    ///
+    /// Fails with a [`VersionConflict`] error if the version does not match.
+    async fn compare_and_set(&self, env: &EnvName, value: &Value, expected: &str) -> Result<()> {
+        let _ = (env, value, expected);
+        Err(Unsupported("conditional writes").into())
+    }
+
+    /// List past revisions of a template, most recent first.
+    async fn history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+        let _ = env;
+        bail!("storage backend does not keep template history")
+    }
+
+    /// Retrieve a past revision of a template.
+    async fn get_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+        let _ = (env, id);
+        bail!("storage backend does not keep template history")
+    }
 }
 
-impl Storage {
-    /// Retrieve a value from storage.
-    #[tracing::instrument(skip(self))]
-    pub(crate) async fn get(&self, env: &str) -> Result<Value> {
-        info!("reading template");
-        match self {
-            Storage::S3(s3, bucket) => {
-                let key = format!("{env}.json");
-                let resp = s3.get_object().bucket(bucket).key(key).send().await?;
-                let data = resp.body.collect().await?;
-                let bytes = data.into_bytes();
-                Ok(serde_json::from_str(str::from_utf8(&bytes)?)?)
+/// Implement [`TemplateStore`] for a smart pointer by forwarding every method.
+macro_rules! forward_template_store {
+    ($($pointer:ident),*) => {$(
+        #[async_trait]
+        impl<S: TemplateStore + ?Sized> TemplateStore for $pointer<S> {
+            async fn get(&self, env: &EnvName) -> Result<Value> {
+                (**self).get(env).await
             }
-            Storage::LocalFS(path) => {
-                let path = path.join(format!("{env}.json"));
-                let data = fs::read(&path).await?;
-                Ok(serde_json::from_slice(&data)?)
+
+            async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+                (**self).set(env, value).await
             }
-            Storage::Memory(map) => Ok(map.lock().get(env).context("missing template")?.clone()),
-        }
-    }
 
-    /// Set a value in storage.
-    #[tracing::instrument(skip(self))]
-    pub(crate) async fn set(&self, env: &str, value: &Value) -> Result<()> {
-        info!("writing template");
-        match self {
-            Storage::S3(s3, bucket) => {
-                let key = format!("{env}.json");
-                let content = serde_json::to_vec_pretty(value)?.into();
-                s3.put_object()
-                    .bucket(bucket)
-                    .key(key)
-                    .body(content)
-                    .send()
-                    .await?;
+            async fn delete(&self, env: &EnvName) -> Result<()> {
+                (**self).delete(env).await
+            }
+
+            async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+                (**self).versions().await
+            }
+
+            async fn list(&self) -> Result<Vec<EnvName>> {
+                (**self).list().await
             }
-            Storage::LocalFS(path) => {
-                let path = path.join(format!("{env}.json"));
-                let content = serde_json::to_vec_pretty(value)?;
-                fs::write(&path, &content).await?;
+
+            fn watch(&self) -> Option<broadcast::Receiver<EnvName>> {
+                (**self).watch()
+            }
+
+            async fn compare_and_set(
+                &self,
+                env: &EnvName,
+                value: &Value,
+                expected: &str,
+            ) -> Result<()> {
+                (**self).compare_and_set(env, value, expected).await
             }
-            Storage::Memory(map) => {
-                map.lock().insert(env.into(), value.clone());
+
+            async fn history(&self, env: &EnvName) -> Result<Vec<Revision>> {
+                (**self).history(env).await
+            }
+
+            async fn get_revision(&self, env: &EnvName, id: &str) -> Result<Value> {
+                (**self).get_revision(env, id).await
             }
         }
+    )*};
+}
+
+forward_template_store!(Box, Arc);
+
+/// A past version of a template, as recorded by a storage backend.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revision {
+    /// Backend-specific identifier of this revision.
+    pub id: String,
+
+    /// Name of the author of this change, if known.
+    pub author: Option<String>,
+
+    /// Description of this change, if any.
+    pub message: Option<String>,
+
+    /// Time of this change, in seconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+/// Error returned when a conditional write finds that the template is not at
+/// the expected version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionConflict {
+    /// Version that the writer expected the template to be at.
+    pub expected: String,
+
+    /// Current version of the template, or `None` if it does not exist.
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.actual {
+            Some(actual) => write!(
+                f,
+                "expected template version {}, found {actual}",
+                self.expected
+            ),
+            None => write!(
+                f,
+                "expected template version {}, found no template",
+                self.expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+/// Error returned when a storage backend does not support an optional
+/// operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unsupported(pub &'static str);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage backend does not support {}", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Error returned when reading a template that does not exist.
+///
+/// Backends return this only when they know the template is missing, so that
+/// it can be told apart from the backend being unavailable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateNotFound(pub EnvName);
+
+impl fmt::Display for TemplateNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "missing template {}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateNotFound {}
+
+/// Persists templates to an S3 bucket, under a key prefix.
+#[derive(Clone, Debug)]
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+    server_side_encryption: Option<ServerSideEncryption>,
+    kms_key_id: Option<String>,
+    storage_class: Option<StorageClass>,
+    tags: Vec<(String, String)>,
+}
+
+/// Create an S3 client, optionally for a custom S3-compatible endpoint such as
+/// MinIO.
+///
+/// Buckets are always addressed by path, as S3-compatible services expect.
+pub fn s3_client(sdk_config: &SdkConfig, endpoint: Option<&str>) -> Result<aws_sdk_s3::Client> {
+    let mut config = aws_sdk_s3::config::Builder::from(sdk_config);
+    if let Some(endpoint) = endpoint {
+        let uri: Uri = endpoint.parse().context("invalid S3 endpoint")?;
+        config = config.endpoint_resolver(Endpoint::immutable(uri));
+    }
+    Ok(aws_sdk_s3::Client::from_conf(config.build()))
+}
+
+impl S3Store {
+    /// Use an S3 bucket, storing templates under a key prefix.
+    ///
+    /// A `/` is appended to a non-empty prefix if it does not end with one,
+    /// so that the prefix behaves like a directory.
+    pub fn new(client: aws_sdk_s3::Client, bucket: &str, prefix: &str) -> Self {
+        let mut prefix = prefix.trim_start_matches('/').to_owned();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        Self {
+            client,
+            bucket: bucket.into(),
+            prefix,
+            server_side_encryption: None,
+            kms_key_id: None,
+            storage_class: None,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Encrypt new objects on the server side, such as with `aws:kms`.
+    pub fn server_side_encryption(mut self, mode: ServerSideEncryption) -> Self {
+        self.server_side_encryption = Some(mode);
+        self
+    }
+
+    /// Encrypt new objects with a specific KMS key, implying `aws:kms`
+    /// server-side encryption unless another mode is set.
+    pub fn kms_key_id(mut self, key_id: &str) -> Self {
+        self.kms_key_id = Some(key_id.into());
+        self
+    }
+
+    /// Set the storage class of new objects.
+    pub fn storage_class(mut self, storage_class: StorageClass) -> Self {
+        self.storage_class = Some(storage_class);
+        self
+    }
+
+    /// Add a tag to new objects, such as for cost attribution.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.push((key.into(), value.into()));
+        self
+    }
+
+    fn key(&self, env: &EnvName) -> String {
+        format!("{}{env}.json", self.prefix)
+    }
+
+    /// Return object tags in the URL-encoded form expected by S3.
+    fn tagging(&self) -> Result<Option<String>> {
+        if self.tags.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_urlencoded::to_string(&self.tags)?))
+    }
+}
+
+#[async_trait]
+impl TemplateStore for S3Store {
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, env: &EnvName) -> Result<Value> {
+        info!("reading template");
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(env))
+            .send()
+            .await;
+        let resp = match resp {
+            Err(SdkError::ServiceError { err, .. }) if err.is_no_such_key() => {
+                return Err(TemplateNotFound(env.clone()).into());
+            }
+            resp => resp?,
+        };
+        let data = resp.body.collect().await?;
+        let bytes = data.into_bytes();
+        Ok(serde_json::from_str(str::from_utf8(&bytes)?)?)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        info!("writing template");
+        let content = serde_json::to_vec_pretty(value)?.into();
+        let server_side_encryption = match (&self.server_side_encryption, &self.kms_key_id) {
+            (None, Some(_)) => Some(ServerSideEncryption::from("aws:kms")),
+            (mode, _) => mode.clone(),
+        };
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.key(env))
+            .content_type("application/json")
+            .set_server_side_encryption(server_side_encryption)
+            .set_ssekms_key_id(self.kms_key_id.clone())
+            .set_storage_class(self.storage_class.clone())
+            .set_tagging(self.tagging()?)
+            .body(content)
+            .send()
+            .await?;
         Ok(())
     }
 
-    /// List all of the templates in storage.
     #[tracing::instrument(skip(self))]
-    pub(crate) async fn list(&self) -> Result<Vec<String>> {
-        info!("listing templates");
-        match self {
-            Storage::S3(s3, bucket) => {
-                let objects = s3.list_objects_v2().bucket(bucket).send().await?;
-                Ok(objects
-                    .contents()
-                    .unwrap_or_default()
-                    .iter()
-                    .filter_map(|object| Some(object.key()?.strip_suffix(".json")?.to_owned()))
-                    .collect())
+    async fn delete(&self, env: &EnvName) -> Result<()> {
+        info!("deleting template");
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.key(env))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        info!("listing template versions");
+        Ok(list_objects(&self.client, &self.bucket, &self.prefix)
+            .await?
+            .iter()
+            .filter_map(|object| {
+                let env = s3_env(object, &self.prefix)?;
+                Some((env, object.e_tag()?.to_owned()))
+            })
+            .collect())
+    }
+}
+
+/// Persists templates as JSON files in a local directory.
+///
+/// Templates in nested namespaces are stored in subdirectories.
+#[derive(Clone, Debug)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    /// Use a local directory, which is created when first written to.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, env: &EnvName) -> PathBuf {
+        self.root.join(format!("{env}.json"))
+    }
+}
+
+#[async_trait]
+impl TemplateStore for LocalStore {
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, env: &EnvName) -> Result<Value> {
+        info!("reading template");
+        let data = match fs::read(self.path(env)).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(TemplateNotFound(env.clone()).into());
             }
-            Storage::LocalFS(path) => {
-                let mut dir = fs::read_dir(&path).await?;
-                let mut results = Vec::new();
-                while let Some(entry) = dir.next_entry().await? {
-                    if let Some(name) = entry.file_name().to_str() {
-                        if let Some(env) = name.strip_suffix(".json") {
-                            results.push(env.to_owned());
-                        }
-                    }
+            data => data?,
+        };
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        info!("writing template");
+        let (root, path) = (self.root.clone(), self.path(env));
+        let content = serde_json::to_vec_pretty(value)?;
+        task::spawn_blocking(move || write_atomic(&root, &path, &content)).await?
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete(&self, env: &EnvName) -> Result<()> {
+        info!("deleting template");
+        let (root, path) = (self.root.clone(), self.path(env));
+        task::spawn_blocking(move || remove_locked(&root, &path)).await?
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        info!("listing template versions");
+        let mut results = HashMap::new();
+        for env in walk_local(&self.root).await? {
+            // Modification times may be too coarse to tell writes apart, so
+            // the tag is a hash of the contents.
+            let data = match fs::read(self.path(&env)).await {
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                data => data?,
+            };
+            let tag = format!("{:x}", Sha256::digest(&data));
+            results.insert(env, tag);
+        }
+        Ok(results)
+    }
+
+    async fn list(&self) -> Result<Vec<EnvName>> {
+        walk_local(&self.root).await
+    }
+}
+
+/// Only stores templates in memory, which is useful for testing.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    templates: Mutex<HashMap<EnvName, Value>>,
+}
+
+impl MemoryStore {
+    /// Create an empty in-memory store.
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+#[async_trait]
+impl TemplateStore for MemoryStore {
+    async fn get(&self, env: &EnvName) -> Result<Value> {
+        let templates = self.templates.lock();
+        let template = templates
+            .get(env)
+            .ok_or_else(|| TemplateNotFound(env.clone()))?;
+        Ok(template.clone())
+    }
+
+    async fn set(&self, env: &EnvName, value: &Value) -> Result<()> {
+        self.templates.lock().insert(env.clone(), value.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, env: &EnvName) -> Result<()> {
+        self.templates.lock().remove(env);
+        Ok(())
+    }
+
+    async fn versions(&self) -> Result<HashMap<EnvName, String>> {
+        let templates = self.templates.lock();
+        Ok(templates
+            .iter()
+            .map(|(env, value)| (env.clone(), value.to_string()))
+            .collect())
+    }
+}
+
+/// Name of the advisory lock file held while writing to a local directory.
+const LOCK_FILE: &str = ".cadre.lock";
+
+/// Durably replace the contents of a file inside the `root` directory.
+///
+/// The data is written to a temporary file that is synced to disk, then
+/// renamed over the destination, so readers never observe a partial write. The
+/// permissions of an existing file are kept, and new files get mode 0644. An
+/// exclusive advisory lock on `root` keeps processes sharing the directory from
+/// interleaving their writes.
+fn write_atomic(root: &Path, path: &Path, content: &[u8]) -> Result<()> {
+    let dir = path.parent().context("template path has no parent")?;
+    std::fs::create_dir_all(dir)?;
+
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(root.join(LOCK_FILE))?;
+    lock.lock_exclusive()?;
+
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(content)?;
+    // Temporary files are only accessible by their owner, so match the file
+    // being replaced, or the usual mode of a new file.
+    match std::fs::metadata(path) {
+        Ok(metadata) => file.as_file().set_permissions(metadata.permissions())?,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            #[cfg(unix)]
+            file.as_file()
+                .set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o644))?;
+        }
+        Err(err) => return Err(err.into()),
+    }
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+
+    // Sync the directory as well, so that the rename itself is durable.
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+
+    // The advisory lock is released when `lock` is dropped.
+    Ok(())
+}
+
+/// Remove a file inside the `root` directory, if it exists, while holding the
+/// same advisory lock as [`write_atomic`].
+fn remove_locked(root: &Path, path: &Path) -> Result<()> {
+    let lock = match std::fs::File::open(root.join(LOCK_FILE)) {
+        Ok(lock) => lock,
+        // Nothing has ever been written to this directory.
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    lock.lock_exclusive()?;
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// List every object in an S3 bucket under a prefix, following pagination.
+async fn list_objects(s3: &aws_sdk_s3::Client, bucket: &str, prefix: &str) -> Result<Vec<Object>> {
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let resp = s3
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+        objects.extend_from_slice(resp.contents().unwrap_or_default());
+        match resp.next_continuation_token() {
+            Some(token) => continuation_token = Some(token.to_owned()),
+            None => return Ok(objects),
+        }
+    }
+}
+
+/// Get the environment name of a template stored in S3.
+///
+/// Objects whose keys do not form a valid environment name are ignored.
+fn s3_env(object: &Object, prefix: &str) -> Option<EnvName> {
+    let env = object.key()?.strip_prefix(prefix)?.strip_suffix(".json")?;
+    env.parse().ok()
+}
+
+/// Find all templates in a local directory and its subdirectories, which
+/// hold the templates of nested namespaces.
+///
+/// Files and directories whose names do not form a valid environment name are
+/// ignored, which includes hidden and temporary files. A missing root directory
+/// has not been written to yet, so it holds no templates.
+async fn walk_local(root: &Path) -> Result<Vec<EnvName>> {
+    let mut results = Vec::new();
+    if let Err(err) = fs::metadata(root).await {
+        if err.kind() == ErrorKind::NotFound {
+            return Ok(results);
+        }
+    }
+    let mut stack = vec![(root.to_path_buf(), String::new())];
+    while let Some((path, namespace)) = stack.pop() {
+        let mut dir = fs::read_dir(&path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let file_name = match entry.file_name().into_string() {
+                Ok(file_name) => file_name,
+                Err(_) => continue,
+            };
+            if entry.file_type().await?.is_dir() {
+                let name = format!("{namespace}{file_name}");
+                if EnvName::new(name.as_str()).is_ok() {
+                    stack.push((entry.path(), name + "/"));
+                }
+            } else if let Some(env) = file_name.strip_suffix(".json") {
+                if let Ok(env) = EnvName::new(format!("{namespace}{env}")) {
+                    results.push(env);
                 }
-                Ok(results)
             }
-            Storage::Memory(map) => Ok(map.lock().keys().cloned().collect()),
         }
     }
+    Ok(results)
 }
 
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
+    use aws_sdk_s3::model::Object;
+    use aws_types::sdk_config::SdkConfig;
     use serde_json::json;
 
-    use super::Storage;
+    use super::{s3_client, s3_env, LocalStore, MemoryStore, S3Store, TemplateStore};
+
+    #[test]
+    fn s3_env_names() {
+        let env = |key: &str, prefix: &str| {
+            let object = Object::builder().key(key).build();
+            s3_env(&object, prefix).map(String::from)
+        };
+        assert_eq!(env("hello.json", ""), Some("hello".into()));
+        assert_eq!(
+            env("cadre/ns/hello.json", "cadre/ns/"),
+            Some("hello".into())
+        );
+        assert_eq!(env("cadre/ns/hello.txt", "cadre/ns/"), None);
+        assert_eq!(env("other/hello.json", "cadre/ns/"), None);
+        assert_eq!(env("cadre/ns/.hidden.json", "cadre/ns/"), None);
+    }
+
+    #[test]
+    fn s3_object_tags() -> Result<()> {
+        let client = s3_client(&SdkConfig::builder().build(), Some("http://localhost:9000"))?;
+        let store = S3Store::new(client, "bucket", "cadre");
+        assert_eq!(store.tagging()?, None);
+        let store = store.tag("team", "infra").tag("cost center", "a&b");
+        assert_eq!(
+            store.tagging()?.as_deref(),
+            Some("team=infra&cost+center=a%26b")
+        );
+        assert!(s3_client(&SdkConfig::builder().build(), Some("not a uri")).is_err());
+        Ok(())
+    }
 
     #[tokio::test]
     async fn memory_operations() -> Result<()> {
-        let storage = Storage::Memory(Default::default());
+        let storage = MemoryStore::new();
+        let hello = "hello".parse()?;
 
-        assert!(storage.get("hello").await.is_err());
+        assert!(storage.get(&hello).await.is_err());
         assert!(storage.list().await?.is_empty());
 
-        storage.set("hello", &json!("world")).await?;
-        assert_eq!(storage.get("hello").await?, json!("world"));
-        assert_eq!(storage.list().await?, vec![String::from("hello")]);
+        storage.set(&hello, &json!("world")).await?;
+        assert_eq!(storage.get(&hello).await?, json!("world"));
+        assert_eq!(storage.list().await?, vec![hello.clone()]);
+        assert!(storage.history(&hello).await.is_err());
+
+        let versions = storage.versions().await?;
+        storage.set(&hello, &json!("there")).await?;
+        assert_ne!(storage.versions().await?, versions);
+
+        storage.delete(&hello).await?;
+        storage.delete(&hello).await?;
+        assert!(storage.get(&hello).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_fs_operations() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LocalStore::new(dir.path());
+        let hello = "hello".parse()?;
+
+        assert!(storage.get(&hello).await.is_err());
+        assert!(storage.list().await?.is_empty());
+        storage.delete(&hello).await?;
+
+        storage.set(&hello, &json!({ "a": 1 })).await?;
+        storage.set(&hello, &json!({ "a": 2 })).await?;
+        assert_eq!(storage.get(&hello).await?, json!({ "a": 2 }));
+        assert_eq!(storage.list().await?, vec![hello.clone()]);
+
+        // Only the template and the lock file remain, with no temporary files.
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())?
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, [".cadre.lock", "hello.json"]);
+
+        // Namespaced templates are stored in subdirectories.
+        let nested = "team/service/prod".parse()?;
+        storage.set(&nested, &json!({ "b": 3 })).await?;
+        assert!(dir.path().join("team/service/prod.json").is_file());
+        assert_eq!(storage.get(&nested).await?, json!({ "b": 3 }));
+        let mut envs = storage.list().await?;
+        envs.sort();
+        assert_eq!(envs, vec![hello.clone(), nested]);
+
+        // Rewriting a template with contents of the same length changes its
+        // version, even within the resolution of modification times.
+        let version = storage.versions().await?[&hello].clone();
+        storage.set(&hello, &json!({ "a": 3 })).await?;
+        assert_ne!(storage.versions().await?[&hello], version);
+
+        storage.delete(&hello).await?;
+        assert!(storage.get(&hello).await.is_err());
+        assert_eq!(storage.versions().await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn local_fs_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let storage = LocalStore::new(dir.path());
+        let hello = "hello".parse()?;
+        let path = dir.path().join("hello.json");
+        let mode = || Ok::<_, std::io::Error>(std::fs::metadata(&path)?.permissions().mode());
+
+        storage.set(&hello, &json!(1)).await?;
+        assert_eq!(mode()? & 0o777, 0o644);
+
+        // Rewriting a template keeps the permissions it was given.
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o640))?;
+        storage.set(&hello, &json!(2)).await?;
+        assert_eq!(mode()? & 0o777, 0o640);
 
         Ok(())
     }
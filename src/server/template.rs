@@ -1,33 +1,207 @@
 //! Engine for populating cadre configuration templates.
 
-use anyhow::Context;
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::Sha256;
 
 use super::resolver::ResolverChain;
 
 /// Marker character used for template strings.
 pub const TEMPLATE_MARK: &str = "*";
 
+/// A templated value that could not be populated.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PopulateError {
+    /// JSON Pointer to the templated key.
+    pub path: String,
+
+    /// Description of the problem, such as a resolver failure.
+    pub message: String,
+}
+
+/// Error returned when a template could not be populated, listing every
+/// templated value that failed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PopulateFailure {
+    /// All failures, in no particular order.
+    pub errors: Vec<PopulateError>,
+}
+
+impl fmt::Display for PopulateFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not populate template")?;
+        for error in &self.errors {
+            write!(f, "; {:?}: {}", error.path, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PopulateFailure {}
+
 /// Populate a JSON value with the results of templated strings.
+///
+/// Every templated value is attempted, and if any fail, the error is a
+/// [`PopulateFailure`] listing all of them.
 pub async fn populate_template(value: &mut Value, chain: &ResolverChain) -> Result<()> {
-    let mut stack = vec![value];
-    while let Some(value) = stack.pop() {
+    populate_template_tracked(value, chain).await?;
+    Ok(())
+}
+
+/// A value in a populated template that was produced by a resolver.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resolution {
+    /// JSON Pointer to the value in the populated template.
+    pub path: String,
+
+    /// Templated string that was resolved, such as `aws:<NAME>`.
+    pub reference: String,
+
+    /// Whether the value came from a secret resolver.
+    pub secret: bool,
+}
+
+/// Where a leaf value in a populated template was defined.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    /// JSON Pointer to the key in the raw template that defined the value.
+    pub path: String,
+
+    /// Templated string that produced the value, if it was resolved.
+    pub resolver: Option<String>,
+}
+
+/// Populate a JSON value like [`populate_template`], returning a record of
+/// every value in the result that was produced by a resolver.
+pub async fn populate_template_tracked(
+    value: &mut Value,
+    chain: &ResolverChain,
+) -> Result<Vec<Resolution>> {
+    let mut errors = Vec::new();
+    let mut resolutions = Vec::new();
+    let mut stack = vec![(String::new(), value)];
+    while let Some((path, value)) = stack.pop() {
         if let Some(map) = value.as_object_mut() {
             for (key, value) in std::mem::take(map) {
                 if let Some(key_raw) = key.strip_prefix(TEMPLATE_MARK) {
-                    let value = value
-                        .as_str()
-                        .with_context(|| format!("templated key {key:?} is of non-string type"))?;
-                    map.insert(key_raw.into(), chain.resolve(value).await?);
+                    let result = match value.as_str() {
+                        Some(value) => chain.resolve(value).await,
+                        None => Err(anyhow!("templated key {key:?} is of non-string type")),
+                    };
+                    match result {
+                        Ok(resolved) => {
+                            let reference = value.as_str().unwrap_or_default();
+                            resolutions.push(Resolution {
+                                path: pointer(&path, key_raw),
+                                reference: reference.into(),
+                                secret: chain.is_secret(reference),
+                            });
+                            map.insert(key_raw.into(), resolved);
+                        }
+                        Err(err) => errors.push(PopulateError {
+                            path: pointer(&path, &key),
+                            message: format!("{err:#}"),
+                        }),
+                    }
                 } else {
                     map.insert(key, value);
                 }
             }
-            stack.extend(map.values_mut());
+            stack.extend(
+                map.iter_mut()
+                    .map(|(key, value)| (pointer(&path, key), value)),
+            );
         }
     }
-    Ok(())
+    match errors.is_empty() {
+        true => Ok(resolutions),
+        false => Err(PopulateFailure { errors }.into()),
+    }
+}
+
+/// Replace every value produced by a secret resolver with a placeholder
+/// holding a fingerprint of the value, so that changes to it can be noticed.
+///
+/// Fingerprints are keyed with `key`, so that clients cannot recover secrets
+/// with few possible values by hashing guesses.
+pub fn redact_secrets(value: &mut Value, resolutions: &[Resolution], key: &[u8]) {
+    let mut secrets: Vec<_> = resolutions
+        .iter()
+        .filter(|resolution| resolution.secret)
+        .map(|resolution| &resolution.path)
+        .collect();
+    // Redact enclosing values before any secrets nested inside them.
+    secrets.sort_unstable();
+    for path in secrets {
+        if let Some(secret) = value.pointer_mut(path) {
+            let fingerprint = fingerprint(secret, key);
+            *secret = Value::String(format!("<redacted hmac:{fingerprint}>"));
+        }
+    }
+}
+
+/// Find where each leaf of a populated template was defined, keyed by JSON
+/// Pointers to the leaves.
+///
+/// Leaves are values other than non-empty objects. Values produced by nested
+/// resolvers are attributed to the innermost resolver, and to the outermost
+/// templated key in the raw template.
+pub fn find_origins(value: &Value, resolutions: &[Resolution]) -> BTreeMap<String, Origin> {
+    let mut origins = BTreeMap::new();
+    let mut stack = vec![(String::new(), value)];
+    while let Some((path, value)) = stack.pop() {
+        match value {
+            Value::Object(map) if !map.is_empty() => {
+                stack.extend(map.iter().map(|(key, value)| (pointer(&path, key), value)));
+            }
+            _ => {
+                let within = |resolution: &&Resolution| {
+                    let rest = path.strip_prefix(&resolution.path);
+                    matches!(rest, Some(rest) if rest.is_empty() || rest.starts_with('/'))
+                };
+                let outermost = resolutions
+                    .iter()
+                    .filter(within)
+                    .min_by_key(|r| r.path.len());
+                let innermost = resolutions
+                    .iter()
+                    .filter(within)
+                    .max_by_key(|r| r.path.len());
+                let origin = Origin {
+                    path: match outermost {
+                        Some(resolution) => templated_pointer(&resolution.path),
+                        None => path.clone(),
+                    },
+                    resolver: innermost.map(|resolution| resolution.reference.clone()),
+                };
+                origins.insert(path, origin);
+            }
+        }
+    }
+    origins
+}
+
+/// Return the pointer to the templated key that populated a value.
+fn templated_pointer(path: &str) -> String {
+    let (parent, key) = path.rsplit_once('/').unwrap_or_default();
+    format!("{parent}/{TEMPLATE_MARK}{key}")
+}
+
+/// Return a short keyed hash identifying a JSON value.
+fn fingerprint(value: &Value, key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(value.to_string().as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())[..16].into()
+}
+
+/// Append a key to a JSON Pointer, escaping it as needed.
+fn pointer(parent: &str, key: &str) -> String {
+    format!("{parent}/{}", key.replace('~', "~0").replace('/', "~1"))
 }
 
 /// Merge two raw template objects, remaining aware of unpopulated values.
@@ -54,8 +228,11 @@ pub fn merge_templates(dest: &mut Value, src: &Value) {
 mod tests {
     use serde_json::json;
 
-    use super::{merge_templates, populate_template};
-    use crate::server::resolver::{EchoJson, ResolverChain};
+    use super::{
+        find_origins, merge_templates, populate_template, populate_template_tracked,
+        redact_secrets, Origin, PopulateFailure,
+    };
+    use crate::server::resolver::{EchoJson, EchoSecret, ResolverChain};
 
     #[test]
     fn merge_templates_basic() {
@@ -184,5 +361,84 @@ mod tests {
 
         let mut value = json!({"*invalid": "$@not@avalidliteral"});
         assert!(populate_template(&mut value, &chain).await.is_err());
+
+        // Every failure is reported, with a path to its templated key.
+        let mut value = json!({"*a": "foo:bar", "b": {"*c/d": 5, "*e": "x"}});
+        let err = populate_template(&mut value, &chain).await.unwrap_err();
+        let mut paths: Vec<_> = err
+            .downcast::<PopulateFailure>()
+            .unwrap()
+            .errors
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        paths.sort_unstable();
+        assert_eq!(paths, ["/*a", "/b/*c~1d", "/b/*e"]);
+    }
+
+    #[tokio::test]
+    async fn redact_secret_values() {
+        let mut chain = ResolverChain::new();
+        chain.add(EchoJson);
+        chain.add(EchoSecret);
+
+        let mut value = json!({
+            "*a": "secret:{\"b\": 1, \"*c\": \"secret:2\"}",
+            "d": {"*e": "echo:3", "*f": "secret:\"hi\""},
+        });
+        let resolutions = populate_template_tracked(&mut value, &chain).await.unwrap();
+        let full = value.clone();
+        assert_eq!(
+            full,
+            json!({"a": {"b": 1, "c": 2}, "d": {"e": 3, "f": "hi"}})
+        );
+
+        redact_secrets(&mut value, &resolutions, b"key");
+        let redacted = value["a"].as_str().unwrap();
+        assert!(redacted.starts_with("<redacted hmac:"), "{redacted}");
+        assert_eq!(value["d"]["e"], json!(3));
+        assert_ne!(value["d"]["f"], json!("hi"));
+
+        // Fingerprints are stable, and change along with the secret or key.
+        let mut again = full.clone();
+        redact_secrets(&mut again, &resolutions, b"key");
+        assert_eq!(again, value);
+        let mut rekeyed = full.clone();
+        redact_secrets(&mut rekeyed, &resolutions, b"other");
+        assert_ne!(rekeyed["d"]["f"], value["d"]["f"]);
+        let mut changed = full;
+        changed["d"]["f"] = json!("bye");
+        redact_secrets(&mut changed, &resolutions, b"key");
+        assert_ne!(changed["d"]["f"], value["d"]["f"]);
+    }
+
+    #[tokio::test]
+    async fn value_origins() {
+        let mut chain = ResolverChain::new();
+        chain.add(EchoJson);
+        chain.add(EchoSecret);
+
+        let mut value = json!({
+            "a": {"b": 1, "*c": "echo:{\"d\": 2, \"*e\": \"secret:3\"}"},
+            "f": [],
+        });
+        let resolutions = populate_template_tracked(&mut value, &chain).await.unwrap();
+        let origin = |path: &str, resolver: Option<&str>| Origin {
+            path: path.into(),
+            resolver: resolver.map(String::from),
+        };
+        let origins: Vec<_> = find_origins(&value, &resolutions).into_iter().collect();
+        assert_eq!(
+            origins,
+            [
+                ("/a/b".into(), origin("/a/b", None)),
+                (
+                    "/a/c/d".into(),
+                    origin("/a/*c", Some("echo:{\"d\": 2, \"*e\": \"secret:3\"}"))
+                ),
+                ("/a/c/e".into(), origin("/a/*c", Some("secret:3"))),
+                ("/f".into(), origin("/f", None)),
+            ]
+        );
     }
 }
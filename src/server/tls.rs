@@ -0,0 +1,248 @@
+//! Serving over HTTPS, with optional verification of client certificates.
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+
+use anyhow::{Context, Result};
+use axum::Router;
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use parking_lot::RwLock;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, RootCertStore, ServerConfig};
+use sha2::{Digest, Sha256};
+use tokio::time::{self, Duration};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+use super::auth::ClientIdentity;
+use crate::pem::{parse_certificates, parse_private_key};
+
+/// How often certificate files are checked for changes.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a client may take to complete the TLS handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Files that TLS settings are loaded from.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    /// PEM file with the server's certificate chain.
+    pub cert: PathBuf,
+
+    /// PEM file with the server's private key.
+    pub key: PathBuf,
+
+    /// PEM file with roots that client certificates are verified against.
+    ///
+    /// Clients without a certificate are still accepted, unless
+    /// `require_client_cert` is set.
+    pub client_ca: Option<PathBuf>,
+
+    /// Reject clients that do not present a valid certificate.
+    pub require_client_cert: bool,
+}
+
+/// TLS settings for the server, reloaded whenever their files change.
+pub struct TlsServer {
+    files: TlsFiles,
+    current: RwLock<(Arc<ServerConfig>, [u8; 32])>,
+}
+
+impl TlsServer {
+    /// Load TLS settings from files.
+    pub fn load(files: TlsFiles) -> Result<Self> {
+        let digest = files.digest();
+        let config = files.server_config()?;
+        Ok(Self {
+            files,
+            current: RwLock::new((config, digest)),
+        })
+    }
+
+    /// Reload the settings if the contents of any of their files have changed
+    /// since they were last loaded, returning whether they were.
+    ///
+    /// If the new files cannot be parsed, the previous settings stay in use.
+    pub fn reload(&self) -> Result<bool> {
+        let digest = self.files.digest();
+        if digest == self.current.read().1 {
+            return Ok(false);
+        }
+        let config = self.files.server_config()?;
+        *self.current.write() = (config, digest);
+        info!(cert = ?self.files.cert, "reloaded TLS certificates");
+        Ok(true)
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::clone(&self.current.read().0))
+    }
+}
+
+impl TlsFiles {
+    /// Hash the contents of every file, since modification times may be too
+    /// coarse to tell writes apart.
+    fn digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for path in [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            match fs::read(path) {
+                Ok(data) => {
+                    hasher.update((data.len() as u64).to_be_bytes());
+                    hasher.update(data);
+                }
+                Err(_) => hasher.update(u64::MAX.to_be_bytes()),
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        let read = |path: &PathBuf| fs::read(path).with_context(|| format!("reading {path:?}"));
+        let certs = parse_certificates(&read(&self.cert)?)?;
+        let key = parse_private_key(&read(&self.key)?)?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in parse_certificates(&read(path)?)? {
+                    roots.add(&cert).context("invalid client CA certificate")?;
+                }
+                builder.with_client_cert_verifier(match self.require_client_cert {
+                    true => AllowAnyAuthenticatedClient::new(roots),
+                    false => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+                })
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("invalid server certificate")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+/// Serve an app over HTTPS.
+///
+/// Requests from clients with a verified certificate carry a
+/// [`ClientIdentity`] extension, taken from the certificate's common name.
+pub async fn serve_tls(listener: TcpListener, app: Router, tls: TlsServer) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let tls = Arc::new(tls);
+    tokio::spawn(reload_certificates(Arc::downgrade(&tls)));
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                // Errors such as running out of file descriptors are transient.
+                warn!(?err, "problem accepting connection");
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = tls.acceptor();
+        let app = app.clone();
+        tokio::spawn(async move {
+            // Clients that never finish the handshake would otherwise hold
+            // their connection open forever.
+            let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    warn!(%addr, ?err, "TLS handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    warn!(%addr, "TLS handshake timed out");
+                    return;
+                }
+            };
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(common_name);
+            let service = service_fn(move |mut req| {
+                if let Some(identity) = &identity {
+                    req.extensions_mut().insert(identity.clone());
+                }
+                app.clone().call(req)
+            });
+            if let Err(err) = Http::new().serve_connection(stream, service).await {
+                warn!(%addr, ?err, "problem serving TLS connection");
+            }
+        });
+    }
+}
+
+/// Background task that reloads certificates when their files change.
+async fn reload_certificates(tls: Weak<TlsServer>) {
+    let mut interval = time::interval(RELOAD_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let tls = match tls.upgrade() {
+            Some(tls) => tls,
+            None => break,
+        };
+        if let Err(err) = tls.reload() {
+            warn!(?err, "problem reloading TLS certificates");
+        }
+    }
+}
+
+/// Return the subject common name of a certificate.
+fn common_name(cert: &Certificate) -> Option<ClientIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(ClientIdentity(name.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+    use rcgen::generate_simple_self_signed;
+
+    use super::{TlsFiles, TlsServer};
+
+    #[test]
+    fn reload_on_change() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let files = TlsFiles {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+            client_ca: None,
+            require_client_cert: false,
+        };
+        let write = |name: &str| -> Result<()> {
+            let cert = generate_simple_self_signed(vec![name.into()])?;
+            fs::write(&files.cert, cert.serialize_pem()?)?;
+            fs::write(&files.key, cert.serialize_private_key_pem())?;
+            Ok(())
+        };
+
+        write("a")?;
+        let tls = TlsServer::load(files.clone())?;
+        assert!(!tls.reload()?);
+        write("b")?;
+        assert!(tls.reload()?);
+        assert!(!tls.reload()?);
+
+        // Invalid files are not loaded, and are retried on the next reload.
+        fs::write(&files.key, "")?;
+        assert!(tls.reload().is_err());
+        assert!(tls.reload().is_err());
+        Ok(())
+    }
+}
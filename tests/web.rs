@@ -3,27 +3,37 @@
 use std::net::TcpListener;
 
 use anyhow::Result;
+use cadre::client::StatusError;
 use cadre::server::{
-    resolver::{EchoJson, ResolverChain},
+    auth::{Access, Credentials},
+    name::EnvName,
+    resolver::{EchoJson, EchoSecret, ResolverChain},
     server,
     state::State,
-    storage::Storage,
+    storage::{LocalStore, MemoryStore},
 };
 use cadre::CadreClient;
+use hyper::StatusCode;
 use serde_json::json;
 use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
 
-async fn spawn_test_server() -> Result<(CadreClient, JoinHandle<()>)> {
+/// Return the status of the response that a client request failed with.
+fn error_status(err: &anyhow::Error) -> Option<StatusCode> {
+    err.downcast_ref::<StatusError>().map(StatusError::status)
+}
+
+/// Spawn a test server, returning its origin.
+async fn spawn_server(credentials: Credentials) -> Result<(String, JoinHandle<()>)> {
     let mut chain = ResolverChain::new();
     chain.add(EchoJson);
-    let storage = Storage::Memory(Default::default());
-    let state = State::new(chain, storage, Some("default"));
+    chain.add(EchoSecret);
+    let state = State::new(chain, MemoryStore::new(), Some("default".parse()?));
 
-    let secret = String::from("test-secret");
-    let app = server(state, secret.clone());
+    let app = server(state, credentials);
 
     let listener = TcpListener::bind("localhost:0")?;
-    let client = CadreClient::new(&format!("http://{}", listener.local_addr()?), &secret);
+    let origin = format!("http://{}", listener.local_addr()?);
 
     let handle = tokio::spawn(async move {
         axum::Server::from_tcp(listener)
@@ -33,7 +43,13 @@ async fn spawn_test_server() -> Result<(CadreClient, JoinHandle<()>)> {
             .unwrap();
     });
 
-    Ok((client, handle))
+    Ok((origin, handle))
+}
+
+async fn spawn_test_server() -> Result<(CadreClient, JoinHandle<()>)> {
+    let secret = "test-secret";
+    let (origin, handle) = spawn_server(Credentials::new(secret)).await?;
+    Ok((CadreClient::new(&origin, secret), handle))
 }
 
 #[tokio::test]
@@ -59,6 +75,23 @@ async fn simple_operations() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn delete_templates() -> Result<()> {
+    let (client, _handle) = spawn_test_server().await?;
+
+    client.write_template("default", &json!({})).await?;
+    client.write_template("hello", &json!({ "a": 1 })).await?;
+    client.delete_template("hello").await?;
+    assert!(client.read_template("hello").await.is_err());
+    assert!(client.load_config("hello").await.is_err());
+    assert_eq!(client.list_configs().await?, vec![String::from("default")]);
+
+    // Removing a template that does not exist is not an error.
+    client.delete_template("hello").await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn override_resolvers() -> Result<()> {
     let (client, _handle) = spawn_test_server().await?;
@@ -86,3 +119,868 @@ async fn override_resolvers() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn cached_config_invalidation() -> Result<()> {
+    let (client, _handle) = spawn_test_server().await?;
+
+    client.write_template("default", &json!({ "a": 1 })).await?;
+    client.write_template("hello", &json!({ "b": 2 })).await?;
+    assert_eq!(
+        client.load_config("hello").await?,
+        json!({ "a": 1, "b": 2 })
+    );
+
+    // Writing the environment itself invalidates its cached config.
+    client.write_template("hello", &json!({ "b": 3 })).await?;
+    assert_eq!(
+        client.load_config("hello").await?,
+        json!({ "a": 1, "b": 3 })
+    );
+
+    // Writing the default template invalidates every cached config.
+    client.write_template("default", &json!({ "a": 4 })).await?;
+    assert_eq!(
+        client.load_config("hello").await?,
+        json!({ "a": 4, "b": 3 })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn mirrored_templates() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let interval = Duration::from_millis(50);
+    let a = State::new(ResolverChain::new(), LocalStore::new(dir.path()), None)
+        .with_mirror(interval)
+        .await?;
+    let b = State::new(ResolverChain::new(), LocalStore::new(dir.path()), None)
+        .with_mirror(interval)
+        .await?;
+
+    let hello: EnvName = "hello".parse()?;
+    a.write_template(&hello, &json!({ "foo": "bar" })).await?;
+    assert_eq!(a.list_configs().await?, vec![hello.clone()]);
+    assert_eq!(a.load_config(&hello).await?, json!({ "foo": "bar" }));
+
+    // Writes made through one replica show up in the other after a sync.
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    while b.list_configs().await?.is_empty() {
+        assert!(time::Instant::now() < deadline, "mirror was not synced");
+        time::sleep(interval / 5).await;
+    }
+    assert_eq!(b.list_configs().await?, vec![hello.clone()]);
+    assert_eq!(b.load_config(&hello).await?, json!({ "foo": "bar" }));
+
+    a.write_template(&hello, &json!({ "foo": "baz" })).await?;
+    while b.load_config(&hello).await? != json!({ "foo": "baz" }) {
+        assert!(time::Instant::now() < deadline, "mirror was not synced");
+        time::sleep(interval / 5).await;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_env_names() -> Result<()> {
+    let (client, _handle) = spawn_test_server().await?;
+
+    for env in [".hidden", "..", "a%2F..%2Fb", "sp%20ace"] {
+        let err = client.write_template(env, &json!({})).await.unwrap_err();
+        assert_eq!(
+            error_status(&err),
+            Some(StatusCode::BAD_REQUEST),
+            "{env}: {err}"
+        );
+        let err = client.read_template(env).await.unwrap_err();
+        assert_eq!(
+            error_status(&err),
+            Some(StatusCode::BAD_REQUEST),
+            "{env}: {err}"
+        );
+        let err = client.load_config(env).await.unwrap_err();
+        assert_eq!(
+            error_status(&err),
+            Some(StatusCode::BAD_REQUEST),
+            "{env}: {err}"
+        );
+    }
+    assert!(client.list_configs().await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn namespaced_environments() -> Result<()> {
+    let (client, _handle) = spawn_test_server().await?;
+
+    client.write_template("default", &json!({})).await?;
+    for env in ["team/a", "team/svc/prod", "team/svc/dev", "other/x"] {
+        client.write_template(env, &json!({ "env": env })).await?;
+    }
+    assert_eq!(
+        client.load_config("team/svc/prod").await?,
+        json!({ "env": "team/svc/prod" })
+    );
+
+    let name = |s: &str| s.parse().unwrap();
+    let root = client.list_namespace("").await?;
+    assert_eq!(root.namespaces, vec![name("other"), name("team")]);
+    assert_eq!(root.configs, vec![name("default")]);
+    let team = client.list_namespace("team").await?;
+    assert_eq!(team.namespaces, vec![name("team/svc")]);
+    assert_eq!(team.configs, vec![name("team/a")]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn namespace_secrets() -> Result<()> {
+    let mut credentials = Credentials::new("test-secret");
+    credentials.add_namespace("team".parse()?, "team-secret");
+    let (origin, _handle) = spawn_server(credentials).await?;
+    let admin = CadreClient::new(&origin, "test-secret");
+    let team = CadreClient::new(&origin, "team-secret");
+
+    admin.write_template("default", &json!({})).await?;
+    admin.write_template("other/x", &json!({})).await?;
+    team.write_template("team/a", &json!({ "a": 1 })).await?;
+    assert_eq!(team.load_config("team/a").await?, json!({ "a": 1 }));
+
+    // Environments outside of the namespace are forbidden and hidden.
+    for env in ["default", "other/x", "team"] {
+        let err = team.read_template(env).await.unwrap_err();
+        assert_eq!(
+            error_status(&err),
+            Some(StatusCode::FORBIDDEN),
+            "{env}: {err}"
+        );
+    }
+    assert!(team.write_template("other/y", &json!({})).await.is_err());
+    assert_eq!(team.list_configs().await?, vec![String::from("team/a")]);
+    let root = team.list_namespace("").await?;
+    assert_eq!(root.namespaces, vec!["team".parse()?]);
+    assert!(root.configs.is_empty());
+
+    assert!(CadreClient::new(&origin, "wrong")
+        .list_configs()
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn redacted_secrets() -> Result<()> {
+    let mut credentials = Credentials::new("test-secret");
+    credentials.add_secret("viewer-secret", Access::all().redacted());
+    let (origin, _handle) = spawn_server(credentials).await?;
+    let admin = CadreClient::new(&origin, "test-secret");
+    let viewer = CadreClient::new(&origin, "viewer-secret");
+
+    let db = json!({ "*db": "secret:{\"password\": \"hunter2\"}" });
+    admin.write_template("default", &db).await?;
+    let template = json!({ "port": 80, "*token": "secret:\"abc\"", "*x": "echo:1" });
+    admin.write_template("app", &template).await?;
+    assert_eq!(
+        admin.load_config("app").await?,
+        json!({ "port": 80, "token": "abc", "x": 1, "db": { "password": "hunter2" } })
+    );
+
+    // Only values from secret resolvers are hidden from the viewer.
+    for config in [
+        viewer.load_config("app").await?,
+        viewer.preview_config("app", &template).await?,
+    ] {
+        assert_eq!(config["port"], json!(80));
+        assert_eq!(config["x"], json!(1));
+        for key in ["token", "db"] {
+            let value = config[key].as_str().unwrap();
+            assert!(value.starts_with("<redacted hmac:"), "{key}: {value}");
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn redacted_clients_are_read_only() -> Result<()> {
+    use hyper::{Body, Client, Method, Request};
+
+    let mut credentials = Credentials::new("test-secret");
+    credentials.add_secret("viewer-secret", Access::all().redacted());
+    let (origin, _handle) = spawn_server(credentials).await?;
+    let admin = CadreClient::new(&origin, "test-secret");
+    admin.write_template("app", &json!({ "a": 1 })).await?;
+
+    let status = |method: Method, path: &str| {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{origin}{path}"))
+            .header("X-Cadre-Secret", "viewer-secret")
+            .header("Content-Type", "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        async move { Ok::<_, anyhow::Error>(Client::new().request(req).await?.status()) }
+    };
+    for path in ["/t/app", "/s/app"] {
+        assert_eq!(status(Method::PUT, path).await?, StatusCode::FORBIDDEN);
+    }
+    assert_eq!(
+        status(Method::DELETE, "/s/app").await?,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(status(Method::GET, "/t/app").await?, StatusCode::OK);
+    assert_eq!(admin.read_template("app").await?, json!({ "a": 1 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn conditional_writes_unsupported() -> Result<()> {
+    use hyper::{header, Body, Client, Request};
+
+    let (origin, _handle) = spawn_server(Credentials::new("test-secret")).await?;
+    let req = Request::put(format!("{origin}/t/app"))
+        .header("X-Cadre-Secret", "test-secret")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::IF_MATCH, "\"1\"")
+        .body(Body::from("{}"))?;
+    let resp = Client::new().request(req).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn explain_sources() -> Result<()> {
+    let (client, _handle) = spawn_test_server().await?;
+
+    let default = json!({ "a": 1, "b": { "c": 2, "d": 3 }, "*e": "secret:\"x\"" });
+    client.write_template("default", &default).await?;
+    let template = json!({ "a": 4, "b": { "*c": "echo:5" } });
+    client.write_template("app", &template).await?;
+
+    let explanation = client.explain_config("app").await?;
+    assert_eq!(explanation.config, client.load_config("app").await?);
+    let sources: Vec<_> = explanation
+        .sources
+        .iter()
+        .map(|(path, source)| {
+            let resolver = source.resolver.as_deref();
+            (
+                path.as_str(),
+                source.env.as_str(),
+                source.path.as_str(),
+                resolver,
+            )
+        })
+        .collect();
+    assert_eq!(
+        sources,
+        [
+            ("/a", "app", "/a", None),
+            ("/b/c", "app", "/b/*c", Some("echo:5")),
+            ("/b/d", "default", "/b/d", None),
+            ("/e", "default", "/*e", Some("secret:\"x\"")),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn validated_configs() -> Result<()> {
+    use cadre::server::schema::SchemaViolation;
+
+    let mut chain = ResolverChain::new();
+    chain.add(EchoSecret);
+    let state = State::new(chain, MemoryStore::new(), None).with_config_validation();
+    let app: EnvName = "app".parse()?;
+    let schema = json!({ "properties": { "port": { "type": "integer" } } });
+    state.write_schema(&app, &schema).await?;
+
+    // Templated values are only checked once they are resolved, even if the
+    // explanation hides them.
+    state
+        .write_template(&app, &json!({ "*port": "secret:\"80\"" }))
+        .await?;
+    let err = state.load_config(&app).await.unwrap_err();
+    assert!(err.is::<SchemaViolation>());
+    for redact in [false, true] {
+        let err = state.explain_config(&app, redact).await.unwrap_err();
+        assert!(err.is::<SchemaViolation>());
+    }
+
+    state
+        .write_template(&app, &json!({ "*port": "secret:80" }))
+        .await?;
+    assert_eq!(state.load_config(&app).await?, json!({ "port": 80 }));
+    let explanation = state.explain_config(&app, true).await?;
+    assert_ne!(explanation.config, json!({ "port": 80 }));
+
+    // Environments too long to have a schema of their own still use those of
+    // their namespaces.
+    let long: EnvName = format!("app/{}", "x".repeat(124)).parse()?;
+    state.write_template(&long, &json!({ "port": 80 })).await?;
+    assert_eq!(state.load_config(&long).await?, json!({ "port": 80 }));
+    let result = state.write_template(&long, &json!({ "port": "80" })).await;
+    assert!(result.unwrap_err().is::<SchemaViolation>());
+    assert!(state.read_schema(&long).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn preview_templates() -> Result<()> {
+    let (client, _handle) = spawn_test_server().await?;
+
+    client.write_template("default", &json!({ "x": 1 })).await?;
+    let template = json!({ "*a": "echo:{\"*b\": \"echo:2\"}" });
+    assert_eq!(
+        client.preview_config("hello", &template).await?,
+        json!({ "a": { "b": 2 }, "x": 1 })
+    );
+    assert!(client.read_template("hello").await.is_err());
+
+    // Resolver errors are reported as an unprocessable template.
+    let template = json!({ "*a": "missing:1", "*b": 2 });
+    let err = client.preview_config("hello", &template).await.unwrap_err();
+    assert_eq!(
+        error_status(&err),
+        Some(StatusCode::UNPROCESSABLE_ENTITY),
+        "{err}"
+    );
+    assert_eq!(client.list_configs().await?, vec![String::from("default")]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn schema_validation() -> Result<()> {
+    use hyper::{Body, Client, Method, Request};
+    use serde_json::Value;
+
+    let secret = "test-secret";
+    let (origin, _handle) = spawn_server(Credentials::new(secret)).await?;
+    let client = CadreClient::new(&origin, secret);
+    let request = |method: Method, path: &str, body: Value| {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{origin}{path}"))
+            .header("X-Cadre-Secret", secret)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        Client::new().request(req)
+    };
+
+    let schema = json!({
+        "type": "object",
+        "properties": { "port": { "type": "integer" } },
+        "required": ["port", "host"],
+    });
+    let resp = request(Method::PUT, "/s/team", schema.clone()).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = request(Method::PUT, "/s/team/a", json!({ "type": 5 })).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // Invalid templates are rejected with every error listed.
+    let resp = request(Method::PUT, "/t/team/a", json!({ "port": "80" })).await?;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = serde_json::from_slice(&hyper::body::to_bytes(resp).await?)?;
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().all(|e| e["schema"] == "team"));
+    assert!(errors.iter().any(|e| e["path"] == "/port"));
+    assert!(client.read_template("team/a").await.is_err());
+
+    // Required properties may be templated or come from the default template.
+    client
+        .write_template("default", &json!({ "host": "x" }))
+        .await?;
+    client
+        .write_template("team/a", &json!({ "*port": "echo:80" }))
+        .await?;
+    client
+        .write_template("other", &json!({ "port": "80" }))
+        .await?;
+
+    let resp = request(Method::GET, "/s/team/a?effective=true", Value::Null).await?;
+    let body: Value = serde_json::from_slice(&hyper::body::to_bytes(resp).await?)?;
+    assert_eq!(body, schema);
+
+    // Schemas are not listed or accessible as templates.
+    assert_eq!(client.list_configs().await?.len(), 3);
+    let err = client.read_template("team.schema").await.unwrap_err();
+    assert_eq!(error_status(&err), Some(StatusCode::BAD_REQUEST), "{err}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn batch_configs() -> Result<()> {
+    let mut credentials = Credentials::new("test-secret");
+    credentials.add_namespace("team".parse()?, "team-secret");
+    let (origin, _handle) = spawn_server(credentials).await?;
+    let client = CadreClient::new(&origin, "test-secret");
+
+    client
+        .write_template("default", &json!({ "*shared": "secret:1" }))
+        .await?;
+    client.write_template("a", &json!({ "a": 1 })).await?;
+    client
+        .write_template("team/b", &json!({ "*b": "echo:2" }))
+        .await?;
+
+    let configs = client.load_configs(&["a", "team/b", "default"]).await?;
+    assert_eq!(configs.len(), 3);
+    assert_eq!(configs["a"], json!({ "a": 1, "shared": 1 }));
+    assert_eq!(configs["team/b"], json!({ "b": 2, "shared": 1 }));
+    assert_eq!(configs["default"], json!({ "shared": 1 }));
+    assert!(client.load_configs(&[]).await?.is_empty());
+
+    // Requests fail as a whole if any config cannot be read.
+    assert!(client.load_configs(&["a", "missing"]).await.is_err());
+    assert!(client.load_configs(&["a", "b.schema"]).await.is_err());
+    let team = CadreClient::new(&origin, "team-secret");
+    assert!(team.load_configs(&["team/b"]).await.is_ok());
+    assert!(team.load_configs(&["team/b", "a"]).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_retries() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::{routing::get, Json, Router};
+
+    // Fails twice with 502 before succeeding, then always hangs.
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&requests);
+    let app = Router::new().route(
+        "/c/*env",
+        get(move || async move {
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(StatusCode::BAD_GATEWAY),
+                2 => Ok(Json(json!({ "a": 1 }))),
+                _ => std::future::pending().await,
+            }
+        }),
+    );
+    let listener = TcpListener::bind("localhost:0")?;
+    let origin = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+    let client = CadreClient::builder(&origin, "test-secret")
+        .request_timeout(Some(Duration::from_millis(100)))
+        .retries(2)
+        .backoff(Duration::from_millis(1), Duration::from_millis(10))
+        .build()?;
+    assert_eq!(client.load_config("hello").await?, json!({ "a": 1 }));
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    // Hung requests time out, and give up after every retry.
+    let err = client.load_config("hello").await.unwrap_err();
+    assert!(err.to_string().contains("timed out"), "{err}");
+    assert_eq!(requests.load(Ordering::SeqCst), 6);
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_failover() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use axum::{routing::get, Json, Router};
+
+    // A replica that hangs on its first read, then fails with 503 until it is
+    // marked as healthy.
+    let (healthy, hung) = (
+        Arc::new(AtomicBool::new(false)),
+        Arc::new(AtomicBool::new(false)),
+    );
+    let (ping_healthy, read_healthy) = (Arc::clone(&healthy), Arc::clone(&healthy));
+    let app = Router::new()
+        .route(
+            "/ping",
+            get(move || async move {
+                match ping_healthy.load(Ordering::SeqCst) {
+                    true => Ok("pong"),
+                    false => Err(StatusCode::SERVICE_UNAVAILABLE),
+                }
+            }),
+        )
+        .route(
+            "/c/*env",
+            get(move || async move {
+                if read_healthy.load(Ordering::SeqCst) {
+                    Ok(Json(json!({ "from": "down" })))
+                } else if !hung.swap(true, Ordering::SeqCst) {
+                    std::future::pending().await
+                } else {
+                    Err(StatusCode::SERVICE_UNAVAILABLE)
+                }
+            }),
+        );
+    let listener = TcpListener::bind("localhost:0")?;
+    let down = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+    let (up, _handle) = spawn_server(Credentials::new("test-secret")).await?;
+    let setup = CadreClient::new(&up, "test-secret");
+    setup.write_template("default", &json!({})).await?;
+    setup
+        .write_template("hello", &json!({ "from": "up" }))
+        .await?;
+
+    // Each client tries the first origin first, so one of them sees a timeout
+    // and the other sees a server error.
+    let mut client = None;
+    for _ in 0..2 {
+        let failover = CadreClient::builder(&down, "test-secret")
+            .add_origin(&up)
+            .retries(0)
+            .request_timeout(Some(Duration::from_millis(200)))
+            .eject_duration(Duration::from_millis(50))
+            .build()?;
+        for _ in 0..4 {
+            let config = failover.load_config("hello").await?;
+            assert_eq!(config, json!({ "from": "up" }));
+        }
+        failover.write_template("other", &json!({})).await?;
+        client = Some(failover);
+    }
+    let client = client.unwrap();
+
+    // Once the replica is back, it passes a health check and is used again.
+    healthy.store(true, Ordering::SeqCst);
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let mut sources = Vec::new();
+    while !sources.contains(&json!("down")) {
+        assert!(time::Instant::now() < deadline, "origin was not restored");
+        sources.push(client.load_config("hello").await?["from"].clone());
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(sources.contains(&json!("up")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_fallback_cache() -> Result<()> {
+    use cadre::server::crypto::{EncryptedStore, KeyProvider, Keyring};
+
+    let dir = tempfile::tempdir()?;
+    let key = Keyring::generate("cache");
+    let cached_client = |origin: &str| -> Result<CadreClient> {
+        let keys = KeyProvider::Local(key.parse()?);
+        CadreClient::builder(origin, "test-secret")
+            .retries(0)
+            .fallback_cache(EncryptedStore::new(LocalStore::new(dir.path()), keys))
+            .build()
+    };
+
+    let (origin, handle) = spawn_server(Credentials::new("test-secret")).await?;
+    let client = cached_client(&origin)?;
+    client.write_template("default", &json!({})).await?;
+    client
+        .write_template("hello", &json!({ "*password": "secret:\"hunter2\"" }))
+        .await?;
+    let loaded = client.load_config_or_cached("hello").await?;
+    assert_eq!(loaded.config, json!({ "password": "hunter2" }));
+    assert_eq!(loaded.cache_age, None);
+    assert!(client.load_config("missing").await.is_err());
+
+    // The cache is encrypted at rest, under a prefix for this client.
+    let prefix = std::fs::read_dir(dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.is_dir())
+        .unwrap();
+    let stored = std::fs::read_to_string(prefix.join("hello.json"))?;
+    assert!(!stored.contains("hunter2"));
+
+    // Once the server is down, the last known config is used instead.
+    handle.abort();
+    let _ = handle.await;
+    let offline = cached_client(&origin)?;
+    let loaded = offline.load_config_or_cached("hello").await?;
+    assert_eq!(loaded.config, json!({ "password": "hunter2" }));
+    assert!(loaded.cache_age.is_some());
+    assert!(offline.load_config("missing").await.is_err());
+
+    // Corrupt entries do not hide why the server could not be reached.
+    std::fs::write(prefix.join("hello.json"), "{}")?;
+    let err = offline.load_config("hello").await.unwrap_err();
+    assert!(err.is::<hyper::Error>(), "{err:?}");
+    assert!(CadreClient::new(&origin, "test-secret")
+        .load_config("hello")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn typed_configs() -> Result<()> {
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Config {
+        name: String,
+        db: Database,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Database {
+        port: u16,
+    }
+
+    let (client, _handle) = spawn_test_server().await?;
+    client.write_template("default", &json!({})).await?;
+    client
+        .write_template(
+            "hello",
+            &json!({ "name": "a", "db": { "*port": "echo:5432" } }),
+        )
+        .await?;
+    let expected = Config {
+        name: "a".into(),
+        db: Database { port: 5432 },
+    };
+    assert_eq!(client.load_config_as::<Config>("hello").await?, expected);
+
+    let mut watched = client
+        .watch_config_as::<Config>("hello", Duration::from_millis(10))
+        .await?;
+    assert_eq!(*watched.borrow(), expected);
+
+    // Values that fail to deserialize report where, and are not watched.
+    client
+        .write_template("hello", &json!({ "name": "a", "db": { "port": "x" } }))
+        .await?;
+    let err = client.load_config_as::<Config>("hello").await.unwrap_err();
+    assert!(err.to_string().starts_with("db.port: "), "{err}");
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(!watched.has_changed()?);
+
+    client
+        .write_template("hello", &json!({ "name": "b", "db": { "port": 1 } }))
+        .await?;
+    time::timeout(Duration::from_secs(5), watched.changed()).await??;
+    assert_eq!(watched.borrow().name, "b");
+
+    Ok(())
+}
+
+#[cfg(feature = "blocking")]
+#[tokio::test]
+async fn blocking_client() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::{routing::get, Json, Router};
+    use cadre::client::blocking;
+
+    // A replica that fails with 503 until it is marked as healthy, counting
+    // the health checks it receives.
+    let (healthy, pings) = (
+        Arc::new(AtomicBool::new(false)),
+        Arc::new(AtomicUsize::new(0)),
+    );
+    let (ping_healthy, read_healthy, ping_count) = (
+        Arc::clone(&healthy),
+        Arc::clone(&healthy),
+        Arc::clone(&pings),
+    );
+    let app = Router::new()
+        .route(
+            "/ping",
+            get(move || async move {
+                ping_count.fetch_add(1, Ordering::SeqCst);
+                match ping_healthy.load(Ordering::SeqCst) {
+                    true => Ok("pong"),
+                    false => Err(StatusCode::SERVICE_UNAVAILABLE),
+                }
+            }),
+        )
+        .route(
+            "/c/*env",
+            get(move || async move {
+                match read_healthy.load(Ordering::SeqCst) {
+                    true => Ok(Json(json!({ "b": "down" }))),
+                    false => Err(StatusCode::SERVICE_UNAVAILABLE),
+                }
+            }),
+        );
+    let listener = TcpListener::bind("localhost:0")?;
+    let down = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+    let (origin, _handle) = spawn_server(Credentials::new("test-secret")).await?;
+    tokio::task::spawn_blocking(move || {
+        let client = blocking::CadreClient::new(&origin, "test-secret")?;
+        client.write_template("default", &json!({ "a": 1 }))?;
+        client.write_template("hello", &json!({ "*b": "echo:2" }))?;
+        assert_eq!(client.read_template("hello")?, json!({ "*b": "echo:2" }));
+        assert_eq!(client.load_config("hello")?, json!({ "a": 1, "b": 2 }));
+        assert_eq!(client.list_configs()?, ["default", "hello"]);
+        assert!(client.load_config("missing").is_err());
+
+        let failover = CadreClient::builder(&down, "test-secret")
+            .add_origin(&origin)
+            .retries(0)
+            .eject_duration(Duration::from_millis(50))
+            .build()?;
+        let client = blocking::CadreClient::from_async(failover)?;
+        assert_eq!(client.load_config("hello")?, json!({ "a": 1, "b": 2 }));
+
+        // Health checks run while the client is idle, and restore the replica
+        // once it is back.
+        healthy.store(true, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(pings.load(Ordering::SeqCst) > 0);
+        let sources: Vec<serde_json::Value> = (0..2)
+            .map(|_| Ok(client.load_config("hello")?["b"].clone()))
+            .collect::<Result<_>>()?;
+        assert!(sources.contains(&json!("down")), "{sources:?}");
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn tls_client_certificates() -> Result<()> {
+    use cadre::client::tls::TlsConfig;
+    use cadre::server::tls::{serve_tls, TlsFiles, TlsServer};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+
+    // Issue a certificate signed by a new CA, returning PEM files for both.
+    let issue = |name: &str| -> Result<(String, String, String)> {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params)?;
+        let mut params = CertificateParams::new(vec!["localhost".into()]);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = Certificate::from_params(params)?;
+        Ok((
+            ca.serialize_pem()?,
+            cert.serialize_pem_with_signer(&ca)?,
+            cert.serialize_private_key_pem(),
+        ))
+    };
+
+    let dir = tempfile::tempdir()?;
+    let (server_ca, server_cert, server_key) = issue("localhost")?;
+    let (client_ca, client_cert, client_key) = issue("deployer")?;
+    let files = TlsFiles {
+        cert: dir.path().join("cert.pem"),
+        key: dir.path().join("key.pem"),
+        client_ca: Some(dir.path().join("client-ca.pem")),
+        require_client_cert: false,
+    };
+    std::fs::write(&files.cert, server_cert)?;
+    std::fs::write(&files.key, server_key)?;
+    std::fs::write(files.client_ca.as_ref().unwrap(), client_ca)?;
+
+    let mut credentials = Credentials::new("test-secret");
+    credentials.add_identity("deployer", Access::namespace("team".parse()?));
+    let state = State::new(ResolverChain::new(), MemoryStore::new(), None);
+    let listener = TcpListener::bind("localhost:0")?;
+    let origin = format!("https://localhost:{}", listener.local_addr()?.port());
+    let tls = TlsServer::load(files.clone())?;
+    tokio::spawn(serve_tls(listener, server(state, credentials), tls));
+
+    // Clients are authorized by the identity in their certificate.
+    let roots = TlsConfig::new()
+        .without_native_roots()
+        .add_root_certificates(server_ca.as_bytes())?;
+    let tls = roots
+        .clone()
+        .identity(client_cert.as_bytes(), client_key.as_bytes())?;
+    let deployer = CadreClient::with_tls(&origin, "", &tls)?;
+    deployer.write_template("team/a", &json!(1)).await?;
+    assert!(deployer.write_template("other", &json!(2)).await.is_err());
+    let admin = CadreClient::with_tls(&origin, "test-secret", &roots)?;
+    assert_eq!(admin.list_configs().await?, ["team/a"]);
+    let anonymous = CadreClient::with_tls(&origin, "", &roots)?;
+    assert!(anonymous.list_configs().await.is_err());
+
+    // Clients must trust the server's CA.
+    assert!(CadreClient::new(&origin, "test-secret")
+        .list_configs()
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "sql")]
+#[tokio::test]
+async fn sql_conditional_writes() -> Result<()> {
+    use cadre::server::sql::SqlStore;
+    use hyper::{header, Body, Client, Request};
+
+    let dir = tempfile::tempdir()?;
+    let url = format!(
+        "sqlite://{}?mode=rwc",
+        dir.path().join("cadre.db").display()
+    );
+    let storage = SqlStore::connect(&url).await?;
+    let state = State::new(ResolverChain::new(), storage, None);
+    let listener = TcpListener::bind("localhost:0")?;
+    let origin = format!("http://{}", listener.local_addr()?);
+    let app = server(state, Credentials::new("test-secret"));
+    tokio::spawn(async move {
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    });
+
+    let put = |version: Option<&str>, body: &str| {
+        let mut req = Request::put(format!("{origin}/t/hello"))
+            .header("X-Cadre-Secret", "test-secret")
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(version) = version {
+            req = req.header(header::IF_MATCH, format!("\"{version}\""));
+        }
+        Client::new().request(req.body(Body::from(body.to_owned())).unwrap())
+    };
+    assert_eq!(put(None, "1").await?.status(), StatusCode::OK);
+    assert_eq!(put(Some("1"), "2").await?.status(), StatusCode::OK);
+    assert_eq!(
+        put(Some("1"), "3").await?.status(),
+        StatusCode::PRECONDITION_FAILED
+    );
+
+    let client = CadreClient::new(&origin, "test-secret");
+    assert_eq!(client.read_template("hello").await?, json!(2));
+    Ok(())
+}
+
+/// Run with `cargo test -- --ignored` against an S3-compatible service, such
+/// as a local MinIO server, with credentials in the usual AWS variables.
+#[tokio::test]
+#[ignore]
+async fn s3_compatible_storage() -> Result<()> {
+    use cadre::server::storage::{s3_client, S3Store, TemplateStore};
+
+    let endpoint = std::env::var("CADRE_TEST_S3_ENDPOINT")?;
+    let bucket = std::env::var("CADRE_TEST_S3_BUCKET")?;
+    let sdk_config = cadre::cli::default_aws_config().await;
+    let client = s3_client(&sdk_config, Some(&endpoint))?;
+    let store = S3Store::new(client, &bucket, "cadre-test").tag("suite", "web");
+
+    let hello: EnvName = "team/hello".parse()?;
+    store.set(&hello, &json!({ "a": 1 })).await?;
+    assert_eq!(store.get(&hello).await?, json!({ "a": 1 }));
+    assert!(store.list().await?.contains(&hello));
+    store.delete(&hello).await?;
+    assert!(store.get(&hello).await.is_err());
+    Ok(())
+}